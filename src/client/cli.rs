@@ -0,0 +1,106 @@
+//! Command line pieces shared by the client binaries.
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use structopt::StructOpt;
+use tokio::time::Duration;
+
+use crate::client::player::PlayerKind;
+use crate::server::Res;
+use crate::server::net_proto::PlayerAction;
+
+// Player options of the binaries, not documented with /// as structopt would take it as their about
+#[derive(StructOpt, Clone, Debug)]
+pub struct PlayerOpt {
+    /// Player used for the playback: simulated, mpv or vlc
+    #[structopt(short, long, default_value = "simulated")]
+    pub player: String,
+    /// Ipc socket of mpv
+    #[structopt(long, default_value = "/tmp/sync-mpv.sock", parse(from_os_str))]
+    pub mpv_socket: PathBuf,
+    /// Attaches to an mpv already listening on its socket instead of spawning one
+    #[structopt(long)]
+    pub mpv_attach: bool,
+    /// Plays without video and audio outputs
+    #[structopt(long)]
+    pub headless: bool,
+}
+
+impl PlayerOpt {
+    pub fn player_kind(&self) -> Res<PlayerKind> {
+        match self.player.as_str() {
+            "simulated" => Ok(PlayerKind::Simulated),
+            #[cfg(unix)]
+            "mpv" => Ok(PlayerKind::Mpv(crate::client::mpv::MpvConfig {
+                socket: self.mpv_socket.clone(),
+                spawn: !self.mpv_attach,
+                headless: self.headless,
+            })),
+            #[cfg(feature = "vlc")]
+            "vlc" => Ok(PlayerKind::Vlc { headless: self.headless }),
+            #[cfg(not(feature = "vlc"))]
+            "vlc" => bail!("This client is built without the vlc feature"),
+            other => bail!("Unknown player {:?}, expected simulated, mpv or vlc", other),
+        }
+    }
+}
+
+pub fn describe_action(action: PlayerAction) -> String {
+    match action {
+        PlayerAction::Play => "played".to_string(),
+        PlayerAction::Pause => "paused".to_string(),
+        PlayerAction::Stop => "stopped".to_string(),
+        PlayerAction::Seek(ms) => format!("seeked to {}", format_position(Duration::from_millis(ms))),
+    }
+}
+
+/// Formats as m:ss, or h:mm:ss from an hour.
+pub fn format_position(position: Duration) -> String {
+    let seconds = position.as_secs();
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// Parses seconds, possibly fractional, or [hh:]mm:ss.
+pub fn parse_position(position: &str) -> Res<Duration> {
+    let mut seconds = 0.0;
+    for part in position.split(':') {
+        let value: f64 = part.parse().with_context(|| format!("Invalid position {:?}", position))?;
+        if value < 0.0 || !value.is_finite() {
+            bail!("Invalid position {:?}", position);
+        }
+        seconds = seconds * 60.0 + value;
+    }
+    Duration::try_from_secs_f64(seconds).with_context(|| format!("Position {:?} out of range", position))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_seconds_and_clock_positions() {
+        assert_eq!(parse_position("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_position("12.5").unwrap(), Duration::from_millis(12500));
+        assert_eq!(parse_position("1:30").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_position("1:02:03").unwrap(), Duration::from_secs(3723));
+    }
+
+    #[test]
+    fn rejects_invalid_positions() {
+        for position in ["", "abc", "1:x", "-5", "inf", "NaN", "1e30"] {
+            assert!(parse_position(position).is_err(), "{:?} accepted", position);
+        }
+    }
+
+    #[test]
+    fn formats_positions() {
+        assert_eq!(format_position(Duration::from_millis(5999)), "0:05");
+        assert_eq!(format_position(Duration::from_secs(90)), "1:30");
+        assert_eq!(format_position(Duration::from_secs(3723)), "1:02:03");
+    }
+}
@@ -1,18 +1,311 @@
-use syncplay::client::{create_client};
-use simple_logger::SimpleLogger;
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use flume::Receiver;
 use log::*;
-use syncplay::server::{Res, OK};
-use tokio::time::Duration;
+use simple_logger::SimpleLogger;
+use structopt::StructOpt;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::select;
+use tokio::time::{timeout, Duration};
+
+use syncplay::ignore;
+use syncplay::client::{create_client_with, Client, ClientConfig};
+use syncplay::client::event::ClientEvent;
+use syncplay::client::cli::{describe_action, parse_position, PlayerOpt};
+use syncplay::server::{PeerId, Res, SessionId, OK};
+use syncplay::server::net_proto::{PeerDTO, Profile, SessionDTO};
+
+/// Time given to the server to send its state after the connection.
+const STATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Client of a sync_daemon server. Without command it starts an interactive prompt, after a
+/// command joining a session it follows the session until Ctrl-C.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "sync")]
+struct Opt {
+    /// Address of the server
+    #[structopt(short, long, default_value = "127.0.0.1:5135")]
+    server: String,
+    /// Nickname shown to the other peers, chosen by the server when empty
+    #[structopt(short, long, default_value = "")]
+    nickname: String,
+    #[structopt(flatten)]
+    player: PlayerOpt,
+    /// Local path of the media played in the session
+    #[structopt(short, long)]
+    media: Option<String>,
+    /// One of off, error, warn, info, debug, trace
+    #[structopt(short, long, default_value = "warn")]
+    log_level: String,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "sync", no_version)]
+enum Command {
+    /// Lists the sessions of the server
+    Sessions,
+    /// Lists the peers connected to the server
+    Peers,
+    /// Creates a session and joins it
+    Create {
+        name: String,
+        #[structopt(default_value = "")]
+        password: String,
+    },
+    /// Joins a session
+    Join {
+        /// Id of the session, or a prefix matching only this session
+        session: String,
+        #[structopt(default_value = "")]
+        password: String,
+    },
+    /// Leaves the current session
+    Leave,
+    /// Starts the playback clock of the session
+    Start,
+    /// Shares a media with the session, played from its start
+    Share {
+        media: String,
+    },
+    Play,
+    Pause,
+    Stop,
+    /// Seeks to a position given in seconds or as [hh:]mm:ss
+    Seek {
+        #[structopt(parse(try_from_str = parse_position))]
+        position: Duration,
+    },
+    /// Shows the current session, its participants and the sync status of the local player
+    Status,
+}
+
+impl Opt {
+    fn client_config(&self) -> Res<ClientConfig> {
+        Ok(ClientConfig {
+            player: self.player.player_kind()?,
+            media: self.media.clone(),
+            profile: Profile { nickname: self.nickname.clone(), color: None },
+            ..ClientConfig::default()
+        })
+    }
+}
 
 #[tokio::main]
 pub async fn main() -> Res {
-    SimpleLogger::new().with_level(LevelFilter::Info).init().unwrap();
-    // Bind a server socket
-    let client = create_client("127.0.0.1:5135").await?;
-    client.create_session("HHHHH".to_string()).await;
-    client.start_session().await;
-    client.play().await;
-
-    tokio::time::sleep(Duration::from_millis(1000)).await;
+    let opt = Opt::from_args();
+    let level = LevelFilter::from_str(&opt.log_level)
+        .with_context(|| format!("Unknown log level {:?}", opt.log_level))?;
+    SimpleLogger::new().with_level(level).init()?;
+
+    let client = create_client_with(opt.server.as_str(), opt.client_config()?).await
+        .with_context(|| format!("Couldn't connect to {}", opt.server))?;
+    let result = run(&client, opt.command).await;
+    // Stops the player, a spawned mpv included
+    client.close().await?;
+    result
+}
+
+async fn run(client: &Client, command: Option<Command>) -> Res {
+    let events = client.events();
+    wait_hub_state(client, &events).await?;
+
+    match command {
+        Some(command) => {
+            let follow = matches!(command, Command::Create { .. } | Command::Join { .. });
+            execute(client, command).await?;
+            if follow {
+                println!("Following the session, Ctrl-C to quit");
+                select! {
+                    _ = print_events(client, events, false) => ignore(),
+                    res = tokio::signal::ctrl_c() => res?,
+                }
+            }
+            OK
+        }
+        None => repl(client, events).await,
+    }
+}
+
+/// Waits for the first hub state, the commands resolve their sessions and peers in it.
+async fn wait_hub_state(client: &Client, events: &Receiver<ClientEvent>) -> Res {
+    timeout(STATE_TIMEOUT, async {
+        while client.hub_state().is_none() {
+            match events.recv_async().await? {
+                ClientEvent::Disconnected => bail!("Disconnected from the server"),
+                event => debug!("Event before the hub state: {:?}", event),
+            }
+        }
+        OK
+    }).await.context("The server didn't send its state")??;
+    if let Some(state) = client.hub_state() {
+        println!("Connected as {}", state.me.pseudo);
+    }
     OK
 }
+
+async fn repl(client: &Client, events: Receiver<ClientEvent>) -> Res {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let printer = print_events(client, events, true);
+    tokio::pin!(printer);
+    println!("Type help for the commands, quit to exit");
+    loop {
+        prompt();
+        let line = select! {
+            line = lines.next_line() => line?,
+            _ = &mut printer => return OK,
+        };
+        let line = match line {
+            Some(line) => line,
+            None => return OK,
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => continue,
+            ["quit"] | ["exit"] => return OK,
+            _ => {}
+        }
+        match Command::from_iter_safe(std::iter::once("").chain(words)) {
+            Ok(command) => {
+                if let Err(e) = execute(client, command).await {
+                    println!("Error: {}", e);
+                }
+            }
+            Err(e) => println!("{}", e.message),
+        }
+    }
+}
+
+fn prompt() {
+    print!("> ");
+    std::io::stdout().flush().ok();
+}
+
+async fn execute(client: &Client, command: Command) -> Res {
+    match command {
+        Command::Sessions => {
+            let sessions = client.sessions();
+            if sessions.is_empty() {
+                println!("No session");
+            }
+            let my_session = client.my_session().map(|s| s.id);
+            for session in sessions {
+                let marker = if Some(session.id) == my_session { "*" } else { " " };
+                println!("{} {}", marker, describe_session(client, &session));
+            }
+        }
+        Command::Peers => {
+            for peer in client.connected_peers() {
+                println!("  {}", describe_peer(client, &peer));
+            }
+        }
+        Command::Create { name, password } => {
+            let session_id = client.create_session(name, password).await?;
+            println!("Session {} created", session_id);
+        }
+        Command::Join { session, password } => {
+            let session_id = find_session(client, &session)?;
+            client.join_session(session_id, password).await?;
+            println!("Joined {}", session_id);
+        }
+        Command::Leave => client.leave_session().await?,
+        Command::Start => client.start_session().await?,
+        Command::Share { media } => client.share_media(media).await?,
+        Command::Play => client.play().await?,
+        Command::Pause => client.pause().await?,
+        Command::Stop => client.stop().await?,
+        Command::Seek { position } => client.seek(position).await?,
+        Command::Status => print_status(client).await?,
+    }
+    OK
+}
+
+async fn print_status(client: &Client) -> Res {
+    let session = match client.my_session() {
+        Some(session) => session,
+        None => {
+            println!("Not in a session");
+            return OK;
+        }
+    };
+    println!("{}", describe_session(client, &session));
+    for peer in session.participants.iter().flatten() {
+        let owner = if peer.id == session.owner { ", owner" } else { "" };
+        println!("  {}{}", describe_peer(client, peer), owner);
+    }
+    let stats = client.drift_stats().await?;
+    println!("Drift {} ms, average {:.1} ms, max {} ms, rate {:.3}, {} seeks over {} samples",
+             stats.last_drift, stats.average_drift, stats.max_drift, stats.rate, stats.seeks, stats.samples);
+    OK
+}
+
+/// Prints the events until the client is disconnected, the interactive prompt is printed again
+/// after each one.
+async fn print_events(client: &Client, events: Receiver<ClientEvent>, interactive: bool) {
+    while let Ok(event) = events.recv_async().await {
+        let message = match event {
+            // The commands already tell when a session is joined
+            ClientEvent::Connected(_) | ClientEvent::HubStateUpdated | ClientEvent::SessionJoined(_) => continue,
+            ClientEvent::ProfileUpdated(peer) => format!("Profile updated: {}", peer.pseudo),
+            ClientEvent::SessionLeft(session_id) => format!("Left the session {}", session_id),
+            ClientEvent::PeerLeft(peer_id) => format!("{} left the session", nickname(client, peer_id)),
+            ClientEvent::Permissions { owner, policy, controllers } => {
+                let controllers: Vec<String> = controllers.into_iter().map(|p| nickname(client, p)).collect();
+                format!("Owner {}, {:?} control, controllers: {}", nickname(client, owner), policy, controllers.join(", "))
+            }
+            ClientEvent::Playback(peer_id, action) => {
+                format!("{} {}", nickname(client, peer_id), describe_action(action))
+            }
+            ClientEvent::ActionRefused(action, error) => format!("You {} in the player but {}", describe_action(action), error),
+            ClientEvent::Error(error) => format!("Error from the server: {}", error),
+            ClientEvent::Reconnecting(attempt) => format!("Connection lost, reconnection attempt {}", attempt),
+            ClientEvent::Reconnected { resumed: true, .. } => "Reconnected, session resumed".to_string(),
+            ClientEvent::Reconnected { resumed: false, .. } => "Reconnected as a new peer".to_string(),
+            ClientEvent::Disconnected => {
+                println!("\nDisconnected from the server");
+                return;
+            }
+        };
+        if interactive {
+            println!("\n{}", message);
+            prompt();
+        } else {
+            println!("{}", message);
+        }
+    }
+}
+
+fn find_session(client: &Client, prefix: &str) -> Res<SessionId> {
+    let matching: Vec<SessionId> = client.sessions().iter()
+        .map(|s| s.id)
+        .filter(|id| id.to_string().starts_with(prefix))
+        .collect();
+    match matching.as_slice() {
+        [session_id] => Ok(*session_id),
+        [] => bail!("No session starts with {}", prefix),
+        _ => bail!("Several sessions start with {}", prefix),
+    }
+}
+
+fn nickname(client: &Client, peer_id: PeerId) -> String {
+    client.connected_peers().into_iter()
+        .find(|p| p.id == peer_id)
+        .map(|p| p.pseudo)
+        .unwrap_or_else(|| peer_id.to_string())
+}
+
+fn describe_peer(client: &Client, peer: &PeerDTO) -> String {
+    let me = client.hub_state().map(|s| s.me.id);
+    let you = if Some(peer.id) == me { " (you)" } else { "" };
+    format!("{} {}{}", peer.id, peer.pseudo, you)
+}
+
+fn describe_session(client: &Client, session: &SessionDTO) -> String {
+    let participants = session.participants.as_ref().map_or(0, Vec::len);
+    let state = if session.started { "started" } else { "waiting" };
+    format!("{} {}, {}, {} participant(s), owner {}",
+            session.id, session.name, state, participants, nickname(client, session.owner))
+}
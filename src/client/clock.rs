@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::server::util::now_micros;
+
+/// Number of ping/pong exchanges kept to estimate the clock offset.
+const SAMPLES: usize = 8;
+
+/// Result of one ping/pong exchange, in µs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClockSample {
+    /// Round trip time without the server processing time.
+    pub rtt: i64,
+    /// Estimated `server clock - local clock`.
+    pub offset: i64,
+}
+
+impl ClockSample {
+    /// Computes the sample the NTP way from the four timestamps of an exchange.
+    pub fn new(client_sent: u64, server_received: u64, server_sent: u64, client_received: u64) -> Self {
+        let (t0, t1, t2, t3) = (client_sent as i64, server_received as i64, server_sent as i64, client_received as i64);
+        ClockSample {
+            rtt: ((t3 - t0) - (t2 - t1)).max(0),
+            offset: ((t1 - t0) + (t2 - t3)) / 2,
+        }
+    }
+}
+
+/// Estimation of the server clock from the last ping/pong exchanges.
+///
+/// The offset retained is the one of the sample with the lowest round trip, it is the one where the
+/// network asymmetry can introduce the smallest error.
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    samples: VecDeque<ClockSample>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self { samples: VecDeque::with_capacity(SAMPLES) }
+    }
+
+    pub fn add_pong(&mut self, client_sent: u64, server_received: u64, server_sent: u64) -> ClockSample {
+        let sample = ClockSample::new(client_sent, server_received, server_sent, now_micros());
+        self.add(sample);
+        sample
+    }
+
+    fn add(&mut self, sample: ClockSample) {
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    fn best(&self) -> Option<&ClockSample> {
+        self.samples.iter().min_by_key(|s| s.rtt)
+    }
+
+    pub fn is_synchronised(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.best().map(|s| Duration::from_micros(s.rtt as u64))
+    }
+
+    /// `server clock - local clock` in µs, 0 until the first pong is received.
+    pub fn offset(&self) -> i64 {
+        self.best().map_or(0, |s| s.offset)
+    }
+
+    /// Converts a server time (µs) in the local clock.
+    pub fn to_local(&self, server_time: u64) -> u64 {
+        (server_time as i64 - self.offset()).max(0) as u64
+    }
+
+    /// Current time in the server clock (µs).
+    pub fn server_now(&self) -> u64 {
+        (now_micros() as i64 + self.offset()).max(0) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_removes_the_server_processing_time() {
+        // Server 500µs ahead, 100µs of network each way, 50µs to answer
+        let sample = ClockSample::new(1_000, 1_600, 1_650, 1_250);
+        assert_eq!(sample, ClockSample { rtt: 200, offset: 500 });
+    }
+
+    #[test]
+    fn sample_of_a_server_behind() {
+        let sample = ClockSample::new(10_000, 7_100, 7_100, 10_200);
+        assert_eq!(sample, ClockSample { rtt: 200, offset: -3_000 });
+    }
+
+    #[test]
+    fn sample_rtt_is_never_negative() {
+        let sample = ClockSample::new(1_000, 1_000, 2_000, 1_500);
+        assert_eq!(sample.rtt, 0);
+    }
+
+    #[test]
+    fn unsynchronised_clock_is_the_local_one() {
+        let sync = ClockSync::new();
+        assert!(!sync.is_synchronised());
+        assert_eq!(sync.rtt(), None);
+        assert_eq!(sync.offset(), 0);
+        assert_eq!(sync.to_local(1_234), 1_234);
+    }
+
+    #[test]
+    fn keeps_the_offset_of_the_lowest_round_trip() {
+        let mut sync = ClockSync::new();
+        sync.add(ClockSample { rtt: 900, offset: 700 });
+        sync.add(ClockSample { rtt: 100, offset: 500 });
+        sync.add(ClockSample { rtt: 400, offset: 300 });
+        assert!(sync.is_synchronised());
+        assert_eq!(sync.offset(), 500);
+        assert_eq!(sync.rtt(), Some(Duration::from_micros(100)));
+        assert_eq!(sync.to_local(10_500), 10_000);
+        assert_eq!(sync.to_local(100), 0);
+    }
+
+    #[test]
+    fn forgets_the_oldest_samples() {
+        let mut sync = ClockSync::new();
+        sync.add(ClockSample { rtt: 10, offset: 1 });
+        for _ in 0..SAMPLES {
+            sync.add(ClockSample { rtt: 50, offset: 2 });
+        }
+        assert_eq!(sync.offset(), 2);
+    }
+}
@@ -0,0 +1,219 @@
+use std::time::Duration;
+
+const RATE_STEPS: f64 = 200.0;
+
+/// Thresholds of the drift correction.
+#[derive(Clone, Copy, Debug)]
+pub struct DriftConfig {
+    /// Drift under which the player is considered in sync.
+    pub tolerance: Duration,
+    /// Drift over which the player seeks instead of adjusting its rate.
+    pub seek_threshold: Duration,
+    /// Maximum deviation of the playback rate from 1.0.
+    pub max_rate_adjustment: f64,
+    /// Time in which a drift should be absorbed by the rate adjustment.
+    pub correction_window: Duration,
+}
+
+impl Default for DriftConfig {
+    fn default() -> Self {
+        DriftConfig {
+            tolerance: Duration::from_millis(40),
+            seek_threshold: Duration::from_millis(1000),
+            max_rate_adjustment: 0.05,
+            correction_window: Duration::from_secs(4),
+        }
+    }
+}
+
+/// Correction to apply on the player.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Correction {
+    None,
+    Rate(f64),
+    /// Seek to the position, after setting the rate back to 1.0 when `reset_rate` is set.
+    Seek { position: Duration, reset_rate: bool },
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DriftStats {
+    /// Last measured drift in ms, positive when the local player is ahead of the session.
+    pub last_drift: i64,
+    /// Exponential moving average of the absolute drift in ms.
+    pub average_drift: f64,
+    /// Largest absolute drift measured in ms.
+    pub max_drift: i64,
+    /// Playback rate currently applied.
+    pub rate: f64,
+    pub samples: u64,
+    pub rate_adjustments: u64,
+    pub seeks: u64,
+}
+
+/// Compares the local position with the expected session one and decides how to catch up.
+///
+/// Small drifts are absorbed by nudging the playback rate proportionally to the drift, big ones
+/// are corrected by a seek to the expected position.
+#[derive(Debug)]
+pub struct DriftController {
+    config: DriftConfig,
+    stats: DriftStats,
+}
+
+impl Default for DriftController {
+    fn default() -> Self {
+        Self::new(DriftConfig::default())
+    }
+}
+
+impl DriftController {
+    pub fn new(config: DriftConfig) -> Self {
+        Self {
+            config,
+            stats: DriftStats { rate: 1.0, ..DriftStats::default() },
+        }
+    }
+
+    pub fn config(&self) -> &DriftConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> DriftStats {
+        self.stats
+    }
+
+    /// Must be called after a correction done outside the controller, e.g. a seek from the session.
+    /// Returns true if the rate was adjusted, the player must then be set back to 1.0.
+    pub fn reset_rate(&mut self) -> bool {
+        let adjusted = (self.stats.rate - 1.0).abs() > f64::EPSILON;
+        self.stats.rate = 1.0;
+        adjusted
+    }
+
+    pub fn update(&mut self, local: Duration, expected: Duration, playing: bool) -> Correction {
+        let drift = local.as_millis() as i64 - expected.as_millis() as i64;
+        let abs_drift = drift.unsigned_abs();
+        self.record(drift);
+
+        if abs_drift > self.config.seek_threshold.as_millis() as u64 || (!playing && abs_drift > self.config.tolerance.as_millis() as u64) {
+            self.stats.seeks += 1;
+            return Correction::Seek { position: expected, reset_rate: self.reset_rate() };
+        }
+
+        let rate = if playing && abs_drift > self.config.tolerance.as_millis() as u64 {
+            let max = self.config.max_rate_adjustment;
+            let adjustment = drift as f64 / self.config.correction_window.as_millis() as f64;
+            // Quantized to not flood the player with new rates at each tick
+            ((1.0 - adjustment.clamp(-max, max)) * RATE_STEPS).round() / RATE_STEPS
+        } else {
+            1.0
+        };
+
+        if (rate - self.stats.rate).abs() > f64::EPSILON {
+            self.stats.rate = rate;
+            self.stats.rate_adjustments += 1;
+            Correction::Rate(rate)
+        } else {
+            Correction::None
+        }
+    }
+
+    fn record(&mut self, drift: i64) {
+        const SMOOTHING: f64 = 0.1;
+        let abs_drift = drift.abs();
+        self.stats.last_drift = drift;
+        self.stats.max_drift = self.stats.max_drift.max(abs_drift);
+        self.stats.average_drift = if self.stats.samples == 0 {
+            abs_drift as f64
+        } else {
+            self.stats.average_drift * (1.0 - SMOOTHING) + abs_drift as f64 * SMOOTHING
+        };
+        self.stats.samples += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn ignores_a_drift_within_the_tolerance() {
+        let mut controller = DriftController::default();
+        assert_eq!(controller.update(ms(10_030), ms(10_000), true), Correction::None);
+        assert_eq!(controller.update(ms(9_970), ms(10_000), false), Correction::None);
+        assert_eq!(controller.stats().rate, 1.0);
+    }
+
+    #[test]
+    fn slows_down_when_ahead_and_speeds_up_when_behind() {
+        let mut controller = DriftController::default();
+        // 200ms over a 4s window
+        assert_eq!(controller.update(ms(10_200), ms(10_000), true), Correction::Rate(0.95));
+        assert_eq!(controller.update(ms(9_900), ms(10_000), true), Correction::Rate(1.025));
+    }
+
+    #[test]
+    fn clamps_the_rate_adjustment() {
+        let mut controller = DriftController::default();
+        assert_eq!(controller.update(ms(9_100), ms(10_000), true), Correction::Rate(1.05));
+    }
+
+    #[test]
+    fn keeps_the_rate_until_it_changes() {
+        let mut controller = DriftController::default();
+        assert_eq!(controller.update(ms(10_200), ms(10_000), true), Correction::Rate(0.95));
+        assert_eq!(controller.update(ms(10_201), ms(10_000), true), Correction::None);
+        assert_eq!(controller.update(ms(10_000), ms(10_000), true), Correction::Rate(1.0));
+        assert_eq!(controller.stats().rate_adjustments, 2);
+    }
+
+    #[test]
+    fn seeks_over_the_threshold() {
+        let mut controller = DriftController::default();
+        assert_eq!(controller.update(ms(15_000), ms(10_000), true), Correction::Seek { position: ms(10_000), reset_rate: false });
+        assert_eq!(controller.stats().seeks, 1);
+    }
+
+    #[test]
+    fn resets_the_rate_adjusted_before_a_seek() {
+        let mut controller = DriftController::default();
+        assert_eq!(controller.update(ms(10_200), ms(10_000), true), Correction::Rate(0.95));
+        assert_eq!(controller.update(ms(15_000), ms(10_000), true), Correction::Seek { position: ms(10_000), reset_rate: true });
+        assert_eq!(controller.stats().rate, 1.0);
+        // The player is back at 1.0, a drift within the tolerance needs nothing more
+        assert_eq!(controller.update(ms(10_010), ms(10_000), true), Correction::None);
+        assert_eq!(controller.update(ms(10_200), ms(10_000), true), Correction::Rate(0.95));
+    }
+
+    #[test]
+    fn tells_whether_the_rate_needs_a_reset() {
+        let mut controller = DriftController::default();
+        assert!(!controller.reset_rate());
+        controller.update(ms(9_800), ms(10_000), true);
+        assert!(controller.reset_rate());
+        assert!(!controller.reset_rate());
+        assert_eq!(controller.stats().rate, 1.0);
+    }
+
+    #[test]
+    fn seeks_when_paused_out_of_the_tolerance() {
+        let mut controller = DriftController::default();
+        assert_eq!(controller.update(ms(10_200), ms(10_000), false), Correction::Seek { position: ms(10_000), reset_rate: false });
+    }
+
+    #[test]
+    fn records_the_drift() {
+        let mut controller = DriftController::default();
+        controller.update(ms(10_100), ms(10_000), true);
+        controller.update(ms(9_800), ms(10_000), true);
+        let stats = controller.stats();
+        assert_eq!(stats.last_drift, -200);
+        assert_eq!(stats.max_drift, 200);
+        assert!((stats.average_drift - 110.0).abs() < 1e-9);
+        assert_eq!(stats.samples, 2);
+    }
+}
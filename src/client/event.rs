@@ -0,0 +1,32 @@
+use crate::server::{PeerId, SessionId};
+use crate::server::net_proto::{ControlPolicy, OutputError, PeerDTO, PlayerAction};
+
+/// What happened to the client, for the applications embedding it.
+#[derive(Clone, Debug)]
+pub enum ClientEvent {
+    /// Handshake done, with the id given by the server.
+    Connected(PeerId),
+    /// Profile accepted by the server, after the connection and each update.
+    ProfileUpdated(PeerDTO),
+    /// The cached hub state changed, it can be read with `Client::hub_state`.
+    HubStateUpdated,
+    SessionJoined(SessionId),
+    SessionLeft(SessionId),
+    /// Another participant left the session.
+    PeerLeft(PeerId),
+    Permissions { owner: PeerId, policy: ControlPolicy, controllers: Vec<PeerId> },
+    /// Playback command applied on the local player, with the peer who initiated it.
+    Playback(PeerId, PlayerAction),
+    /// The server refused an action done by the user in the player, which was set back to the
+    /// session playback.
+    ActionRefused(PlayerAction, OutputError),
+    /// Error sent by the server outside of any request.
+    Error(OutputError),
+    /// The connection to the server is lost, with the number of the reconnection attempt.
+    Reconnecting(u32),
+    /// Reconnected to the server, `resumed` when the server kept the peer and its session, a new
+    /// peer otherwise.
+    Reconnected { id: PeerId, resumed: bool },
+    /// The connection to the server is lost and the reconnection failed, no event follows.
+    Disconnected,
+}
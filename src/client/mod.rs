@@ -1,211 +1,896 @@
 pub mod player;
+pub mod cli;
+pub mod clock;
+pub mod drift;
+pub mod event;
+#[cfg(feature = "vlc")]
+pub mod vlc;
+#[cfg(unix)]
+pub mod mpv;
 
-use crate::server::util::{NetWriter, into_framed_split};
-use tokio::net::tcp::OwnedWriteHalf;
-use crate::server::net_proto::{Input, InputAction, HubAction, PlayerAction, Output, OutputError};
-use tokio::net::{TcpStream, ToSocketAddrs};
+use crate::server::util::{NetReader, NetWriter, into_raw_split, read_frame, typed_reader, typed_writer, write_frame};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use crate::server::net_proto::{Input, InputAction, HubAction, PlayerAction, Output, OutputError, ControlPolicy, Profile, PeerDTO, Hello, HelloReply, Refusal, PROTOCOL_VERSION, HubState, HubDelta, DeltaOutcome, SessionDTO, Ack, RequestId, Resume, ResumeToken, SyncReport};
+use anyhow::{anyhow, bail, Context};
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
 use tap::prelude::Pipe;
 use crate::server::{PeerId, SessionId, Res, OK};
 use futures::{SinkExt, TryStreamExt};
-use flume::{unbounded, Receiver, Sender};
-use tokio::time::{Duration, interval};
+use flume::{bounded, unbounded, Receiver, Sender, TrySendError};
+use tokio::time::{Duration, interval, sleep, timeout};
 use tokio::select;
+use tokio::task::JoinHandle;
 use log::*;
-use crate::client::player::PlayerManager;
+use crate::client::player::{PlayerManager, PlayerKind, PlayerEvent, PlayerState, PlayerReport, SharedReport};
+use crate::client::clock::ClockSync;
+use crate::client::drift::{DriftConfig, DriftController, DriftStats, Correction};
+use crate::client::event::ClientEvent;
+use crate::server::util::now_micros;
+use crate::{ignore, Builder, Ignore};
+use std::sync::{Arc, RwLock};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 
 
 const ALIVE_TICK: u64 = 100;
+const REQUEST_TIMEOUT: u64 = 5000;
+/// Alive ticks without any message after which the connection is lost, the server answers each ping.
+const SILENT_TICKS: u32 = 30;
+/// Alive ticks between two sync reports.
+const REPORT_TICKS: u64 = 10;
+/// Events kept while the application doesn't read them, the next ones are dropped.
+const EVENT_CAPACITY: usize = 1024;
 
 #[derive(Debug)]
 pub enum ProxyMessage {
     ServerMessage(Output),
     Alive,
-    CreateSession(String),
-    JoinSession(SessionId),
-    PauseSession,
-    StartSession,
-    StopSession,
-    PlaySession,
+    /// Input sent as a request, the answer of the server is sent back.
+    Request(InputAction, Sender<Res<Ack>>),
+    /// Joins the session with its password, answered once the server confirmed it.
+    JoinSession(SessionId, String, Sender<Res>),
+    DriftStats(Sender<DriftStats>),
+    PlayerEvent(PlayerEvent),
+    /// The connection with this number to the server is lost.
+    Disconnected(u64),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ClientConfig {
+    pub drift: DriftConfig,
+    pub player: PlayerKind,
+    /// Local path of the media played in the session.
+    pub media: Option<String>,
+    pub profile: Profile,
+    pub reconnect: ReconnectConfig,
+}
+
+/// Reconnection attempts after the loss of the connection, the delay doubles after each failure.
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectConfig {
+    /// 0 disables the reconnection.
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            max_attempts: 10,
+            initial_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+        }
+    }
 }
 
 pub async fn create_client(ip: impl ToSocketAddrs) -> anyhow::Result<Client> {
-    let (mut rs, mut ws) =
-        TcpStream::connect(ip).await?.pipe(|s| into_framed_split(s));
+    create_client_with(ip, ClientConfig::default()).await
+}
+
+pub async fn create_client_with(ip: impl ToSocketAddrs, config: ClientConfig) -> anyhow::Result<Client> {
+    // Resolved once to reconnect to the same server
+    let server: Vec<SocketAddr> = lookup_host(ip).await?.collect();
+    let connection = connect(&server, &config.profile, None).await?;
     let (tx, rx) = unbounded();
+    let (events_tx, events) = bounded(EVENT_CAPACITY);
+    let hub_state = None.rw_lock().arc();
+    let peer_sync = HashMap::new().rw_lock().arc();
+    let (closing, closed) = bounded(1);
 
-    // Handle connection to the sever
-    ws.send(Input { from: None, action: InputAction::Connect }).await?;
-    let mut proxy_client = if let Output::Connected(user_id) = rs.try_next().await?.unwrap() {
-        info!("Connected id: {}", user_id);
-        ClientProxy::new(user_id, ws, rx)
-    } else {
-        todo!("Error protocol received another message")
-    };
+    let reader = connection.reader;
+    let mut proxy_client = ClientProxy::new(connection.id, connection.writer, connection.resume_token, rx, config,
+                                            ConnectionContext { server, proxy_tx: tx.clone(), hub_state: hub_state.clone(),
+                                                                peer_sync: peer_sync.clone(), events: events_tx, closed })?;
+    let player = proxy_client.player_manager.report();
+    proxy_client.emit(ClientEvent::Connected(proxy_client.user_id));
+    proxy_client.listen(reader);
 
-    // Event loop from the server
+    // Events from the player
     {
         let tx = tx.clone();
+        let events = proxy_client.player_manager.events();
         tokio::spawn(async move {
-            let mut interval = interval(Duration::from_millis(ALIVE_TICK));
-            loop {
-                let tick_fut = interval.tick();
-                let rec_message_fut = rs.try_next();
-                select! {
-                    res_message = rec_message_fut => {
-                        let message = res_message.unwrap().unwrap();
-                        debug!("Received message from the server: {:?}", message);
-                        tx.send_async(ProxyMessage::ServerMessage(message)).await.unwrap();
-                    }
-                    _ = tick_fut => {
-                        debug!("Alive tick sent");
-                        tx.send_async(ProxyMessage::Alive).await.unwrap();
-                    }
+            while let Ok(event) = events.recv_async().await {
+                if tx.send_async(ProxyMessage::PlayerEvent(event)).await.is_err() {
+                    break;
                 }
             }
         });
     }
-    tokio::spawn(async move {
-        let e = proxy_client.run().await.unwrap_err();
-        error!("Error in the proxy: {}", e)
+    let proxy = tokio::spawn(async move {
+        match proxy_client.run().await {
+            Ok(()) => info!("Client closed"),
+            Err(e) => error!("Error in the proxy: {}", e),
+        }
     });
-    Ok(Client(tx))
+    Ok(Client { tx, hub_state, peer_sync, player, events, closing, proxy })
+}
+
+/// Connection to the server after the handshake.
+struct Connection {
+    reader: NetReader<Output, OwnedReadHalf>,
+    writer: NetWriter<Input, OwnedWriteHalf>,
+    id: PeerId,
+    resume_token: ResumeToken,
+    resumed: bool,
+}
+
+async fn connect(server: &[SocketAddr], profile: &Profile, resume: Option<Resume>) -> Res<Connection> {
+    let (mut rs, mut ws) =
+        TcpStream::connect(server).await?.pipe(into_raw_split);
+
+    // Handle connection to the sever, see `HelloReply` for the frozen handshake
+    write_frame(&mut ws, PROTOCOL_VERSION).await?;
+    write_frame(&mut ws, Hello::new(profile.clone()).with_resume(resume)).await?;
+    let reply: HelloReply = read_frame(&mut rs).await?.context("The server closed the connection")?;
+    match reply.refusal {
+        None => info!("Server speaks the protocol {} with {:?}", reply.version, reply.features),
+        Some(Refusal::InvalidProtocol) => {
+            bail!("The server speaks the protocol {}, this client {}", reply.version, PROTOCOL_VERSION)
+        }
+        Some(Refusal::LimitReached) => bail!("The server doesn't accept more connections"),
+    }
+    let (mut rs, ws) = (typed_reader(rs), typed_writer(ws));
+    match rs.try_next().await?.context("The server closed the connection")? {
+        Output::Connected { id, resume_token, resumed } => {
+            info!("Connected id: {}", id);
+            Ok(Connection { reader: rs, writer: ws, id, resume_token, resumed })
+        }
+        other => bail!("Unexpected message during the handshake: {:?}", other),
+    }
 }
 
-pub struct Client(Sender<ProxyMessage>);
+/// Event loop from the server, it also ticks the pings. It ends with a `Disconnected` when the
+/// connection is lost or when the server stays silent for `SILENT_TICKS`.
+fn spawn_reader(mut rs: NetReader<Output, OwnedReadHalf>, tx: Sender<ProxyMessage>, connection: u64) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = interval(Duration::from_millis(ALIVE_TICK));
+        let mut silent_ticks = 0;
+        loop {
+            let tick_fut = interval.tick();
+            let rec_message_fut = rs.try_next();
+            let sent = select! {
+                res_message = rec_message_fut => {
+                    match res_message {
+                        Ok(Some(message)) => {
+                            debug!("Received message from the server: {:?}", message);
+                            silent_ticks = 0;
+                            tx.send_async(ProxyMessage::ServerMessage(message)).await
+                        }
+                        Ok(None) => {
+                            info!("The server closed the connection");
+                            tx.send_async(ProxyMessage::Disconnected(connection)).await.ignore();
+                            break;
+                        }
+                        Err(e) => {
+                            error!("Lost the connection to the server: {}", e);
+                            tx.send_async(ProxyMessage::Disconnected(connection)).await.ignore();
+                            break;
+                        }
+                    }
+                }
+                _ = tick_fut => {
+                    silent_ticks += 1;
+                    if silent_ticks > SILENT_TICKS {
+                        error!("The server didn't answer for {}ms", ALIVE_TICK * SILENT_TICKS as u64);
+                        tx.send_async(ProxyMessage::Disconnected(connection)).await.ignore();
+                        break;
+                    }
+                    debug!("Alive tick sent");
+                    tx.send_async(ProxyMessage::Alive).await
+                }
+            };
+            if sent.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// What the proxy shares with the client and needs to reconnect.
+struct ConnectionContext {
+    server: Vec<SocketAddr>,
+    proxy_tx: Sender<ProxyMessage>,
+    hub_state: Arc<RwLock<Option<HubState>>>,
+    peer_sync: Arc<RwLock<HashMap<PeerId, SyncReport>>>,
+    events: Sender<ClientEvent>,
+    /// Disconnected when the client is closed or dropped.
+    closed: Receiver<()>,
+}
+
+pub struct Client {
+    tx: Sender<ProxyMessage>,
+    events: Receiver<ClientEvent>,
+    /// Hub state kept up to date with the deltas sent by the server.
+    hub_state: Arc<RwLock<Option<HubState>>>,
+    peer_sync: Arc<RwLock<HashMap<PeerId, SyncReport>>>,
+    player: SharedReport,
+    /// Never sent, dropped with the client to stop its proxy.
+    closing: Sender<()>,
+    proxy: JoinHandle<()>,
+}
 
 impl Client {
-    pub fn join_session(&self) -> Res {
-        unimplemented!()
+    /// Events of the client, from the connection on. The receivers share the events, each one is
+    /// received once.
+    pub fn events(&self) -> Receiver<ClientEvent> {
+        self.events.clone()
+    }
+
+    /// Copy of the last hub state received, `None` until the server sent the first one.
+    pub fn hub_state(&self) -> Option<HubState> {
+        self.hub_state.read().unwrap().clone()
+    }
+
+    pub fn sessions(&self) -> Vec<SessionDTO> {
+        self.hub_state.read().unwrap().as_ref().map(|s| s.sessions.clone()).unwrap_or_default()
+    }
+
+    pub fn connected_peers(&self) -> Vec<PeerDTO> {
+        self.hub_state.read().unwrap().as_ref().map(|s| s.connected.clone()).unwrap_or_default()
+    }
+
+    pub fn my_session(&self) -> Option<SessionDTO> {
+        self.hub_state.read().unwrap().as_ref().and_then(|s| s.my_session.clone())
     }
 
-    pub async fn create_session(&self, password: String) {
-        self.0.send_async(ProxyMessage::CreateSession(password)).await.unwrap();
+    /// Closes the connection and stops the player, dropping the client does the same without
+    /// waiting for it.
+    pub async fn close(self) -> Res {
+        drop(self.closing);
+        self.proxy.await.context("The client proxy panicked")
     }
 
-    pub async fn start_session(&self) {
-        self.0.send_async(ProxyMessage::StartSession).await.unwrap();
+    /// Last playback reported by each participant of the session, the local peer included.
+    pub fn peer_sync(&self) -> HashMap<PeerId, SyncReport> {
+        self.peer_sync.read().unwrap().clone()
     }
 
-    pub async fn pause(&self) {
-        self.0.send_async(ProxyMessage::PauseSession).await.unwrap();
+    /// Position and state of the local player.
+    pub fn player(&self) -> PlayerReport {
+        *self.player.read().unwrap()
     }
 
-    pub async fn play(&self) {
-        self.0.send_async(ProxyMessage::PlaySession).await.unwrap();
+    /// Joins the session, a refusal is returned as an `OutputError`, e.g. `PasswordDoesntMatch` or
+    /// `UnknownSession`.
+    pub async fn join_session(&self, session_id: SessionId, password: String) -> Res {
+        let (tx, rx) = bounded(1);
+        self.tx.send_async(ProxyMessage::JoinSession(session_id, password, tx)).await.context("The client is stopped")?;
+        timeout(Duration::from_millis(REQUEST_TIMEOUT), rx.recv_async()).await
+            .context("The server didn't answer")?
+            .context("The client is stopped")?
     }
 
-    pub async fn stop(&self) {
-        self.0.send_async(ProxyMessage::StopSession).await.unwrap();
+    /// Sends the action and waits for the answer of the server, a refusal is returned as an
+    /// `OutputError`.
+    async fn request(&self, action: InputAction) -> Res<Ack> {
+        let (tx, rx) = bounded(1);
+        self.tx.send_async(ProxyMessage::Request(action, tx)).await.context("The client is stopped")?;
+        timeout(Duration::from_millis(REQUEST_TIMEOUT), rx.recv_async()).await
+            .context("The server didn't answer")?
+            .context("The client is stopped")?
+    }
+
+    async fn hub_request(&self, action: HubAction) -> Res {
+        self.request(InputAction::HubAction(action)).await?;
+        OK
+    }
+
+    async fn player_request(&self, action: PlayerAction) -> Res {
+        self.request(InputAction::SessionAction(action)).await?;
+        OK
+    }
+
+    /// Creates a session and joins it, the name is refused with `InvalidSessionName` when empty or
+    /// too long.
+    pub async fn create_session(&self, name: String, password: String) -> Res<SessionId> {
+        match self.request(InputAction::HubAction(HubAction::CreateSession(name, password))).await? {
+            Ack::SessionCreated(session_id) => Ok(session_id),
+            other => bail!("Unexpected answer to the session creation: {:?}", other),
+        }
+    }
+
+    pub async fn start_session(&self) -> Res {
+        self.hub_request(HubAction::SessionStart).await
+    }
+
+    /// Shares the media with the session, the participants load it unless they play a local one.
+    pub async fn share_media(&self, media: String) -> Res {
+        self.hub_request(HubAction::Share(media)).await
+    }
+
+    pub async fn leave_session(&self) -> Res {
+        self.hub_request(HubAction::Leave).await
+    }
+
+    pub async fn set_control_policy(&self, policy: ControlPolicy) -> Res {
+        self.hub_request(HubAction::SetControlPolicy(policy)).await
+    }
+
+    pub async fn set_controller(&self, peer_id: PeerId, controller: bool) -> Res {
+        self.hub_request(HubAction::SetController(peer_id, controller)).await
+    }
+
+    pub async fn transfer_ownership(&self, peer_id: PeerId) -> Res {
+        self.hub_request(HubAction::TransferOwnership(peer_id)).await
+    }
+
+    /// Changes the nickname and the colour, the server may alter the nickname to keep it unique.
+    pub async fn update_profile(&self, profile: Profile) -> Res {
+        self.hub_request(HubAction::UpdateProfile(profile)).await
+    }
+
+    pub async fn pause(&self) -> Res {
+        self.player_request(PlayerAction::Pause).await
+    }
+
+    pub async fn play(&self) -> Res {
+        self.player_request(PlayerAction::Play).await
+    }
+
+    pub async fn stop(&self) -> Res {
+        self.player_request(PlayerAction::Stop).await
+    }
+
+    pub async fn seek(&self, position: Duration) -> Res {
+        self.player_request(PlayerAction::Seek(position.as_millis() as u64)).await
+    }
+
+    pub async fn drift_stats(&self) -> Res<DriftStats> {
+        let (tx, rx) = bounded(1);
+        self.tx.send_async(ProxyMessage::DriftStats(tx)).await?;
+        Ok(rx.recv_async().await?)
     }
 }
 
 pub struct ClientProxy {
     user_id: PeerId,
+    resume_token: ResumeToken,
     writer: NetWriter<Input, OwnedWriteHalf>,
+    /// Number of the current connection, the losses reported for the previous ones are ignored.
+    connection: u64,
+    reader: Option<JoinHandle<()>>,
     client_rx: Receiver<ProxyMessage>,
     player_manager: PlayerManager,
+    clock: ClockSync,
+    drift: DriftController,
+    local_media: bool,
+    profile: Option<PeerDTO>,
+    hub_state: Arc<RwLock<Option<HubState>>>,
+    peer_sync: Arc<RwLock<HashMap<PeerId, SyncReport>>>,
+    closed: Receiver<()>,
+    /// Alive ticks since the start, the sync reports are sent every `REPORT_TICKS`.
+    ticks: u64,
+    /// A full hub state has been requested and not received yet.
+    resyncing: bool,
+    /// Last playback of the session received, the player goes back to it when a user action is refused.
+    playback: Option<SessionPlayback>,
+    next_request: RequestId,
+    /// Requests waiting for the answer of the server.
+    pending: HashMap<RequestId, Pending>,
+    events: Sender<ClientEvent>,
+    server: Vec<SocketAddr>,
+    /// Given to the readers of the next connections.
+    proxy_tx: Sender<ProxyMessage>,
+    hello_profile: Profile,
+    reconnect: ReconnectConfig,
+}
+
+enum Pending {
+    Request(Sender<Res<Ack>>),
+    /// Answered at the reception of `Output::Joined`, which comes before the ack.
+    Join(SessionId, Option<Sender<Res>>),
+    /// Action done by the user in the player.
+    UserAction(PlayerAction),
+}
+
+/// Session position sampled at `server_time` (µs, server clock).
+#[derive(Clone, Copy, Debug)]
+struct SessionPlayback {
+    position: Duration,
+    server_time: u64,
+    playing: bool,
 }
 
 impl ClientProxy {
+    /// Handles the messages until the client is closed or every reconnection attempt failed, the
+    /// errors of the other messages are only logged.
     pub async fn run(&mut self) -> Res {
         loop {
-            let message = self.client_rx.recv_async().await?;
+            let message = select! {
+                biased;
+                _ = self.closed.recv_async() => return self.close().await,
+                message = self.client_rx.recv_async() => message?,
+            };
             debug!("Proxy handling message: {:?}", &message);
-            self.handle(message).await?;
+            match message {
+                ProxyMessage::Disconnected(connection) if connection != self.connection => {
+                    debug!("Loss of the previous connection {} ignored", connection);
+                }
+                ProxyMessage::Disconnected(_) => self.reconnect().await?,
+                message => {
+                    if let Err(e) = self.handle(message).await {
+                        warn!("Proxy couldn't handle a message: {}", e);
+                    }
+                }
+            }
         }
     }
 
     async fn handle(&mut self, message: ProxyMessage) -> Res {
         match message {
             ProxyMessage::ServerMessage(message) => {
-                self.handle_server_message(message)
+                self.handle_server_message(message).await
             }
             ProxyMessage::Alive => {
                 self.alive().await
             }
-            ProxyMessage::CreateSession(password) => {
-                self.create_session(password).await
-            }
-            ProxyMessage::JoinSession(_) => {
-                self.join_session().await
+            ProxyMessage::Request(action, answer) => {
+                self.request(action, answer).await
             }
-            ProxyMessage::StartSession => {
-                self.start().await
+            ProxyMessage::JoinSession(session_id, password, answer) => {
+                self.join_session(session_id, password, answer).await
             }
-            ProxyMessage::StopSession => {
-                self.stop().await
-            }
-            ProxyMessage::PlaySession => {
-                self.play().await
+            ProxyMessage::DriftStats(tx) => {
+                tx.send_async(self.drift.stats()).await?;
+                OK
             }
-            ProxyMessage::PauseSession => {
-                self.pause().await
+            ProxyMessage::PlayerEvent(event) => {
+                self.handle_player_event(event).await
             }
+            ProxyMessage::Disconnected(_) => unreachable!("The losses of connection are handled by run"),
         }
     }
 
     pub fn handle_error(&self, error: OutputError) {
         error!("Error received from the server {:?}", error);
+        self.emit(ClientEvent::Error(error));
+    }
+
+    fn emit(&self, event: ClientEvent) {
+        if let Err(TrySendError::Full(event)) = self.events.try_send(event) {
+            trace!("Event {:?} dropped, the application doesn't read them", event);
+        }
+    }
+
+    fn my_session_id(&self) -> Option<SessionId> {
+        self.hub_state.read().unwrap().as_ref().and_then(|s| s.my_session.as_ref()).map(|s| s.id)
     }
 
-    pub fn new(id: PeerId, writer: NetWriter<Input, OwnedWriteHalf>, rec: Receiver<ProxyMessage>) -> Self {
-        Self { writer, user_id: id, client_rx: rec, player_manager: PlayerManager::new() }
+    /// Tells the application that the hub state changed, and if it moved the peer to another session.
+    fn hub_state_updated(&mut self, previous_session: Option<SessionId>) {
+        let session = self.my_session_id();
+        if session != previous_session {
+            self.peer_sync.write().unwrap().clear();
+            self.playback = None;
+            if let Some(session_id) = previous_session {
+                self.emit(ClientEvent::SessionLeft(session_id));
+            }
+            if let Some(session_id) = session {
+                self.emit(ClientEvent::SessionJoined(session_id));
+            }
+        }
+        self.emit(ClientEvent::HubStateUpdated);
     }
 
-    fn handle_server_message(&self, message: Output) -> Res {
+    fn new(id: PeerId, writer: NetWriter<Input, OwnedWriteHalf>, resume_token: ResumeToken, rec: Receiver<ProxyMessage>,
+           config: ClientConfig, context: ConnectionContext) -> Res<Self> {
+        let mut player_manager = PlayerManager::new();
+        player_manager.start(config.player);
+        let local_media = config.media.is_some();
+        if let Some(media) = config.media {
+            player_manager.load(media)?;
+        }
+        Ok(Self {
+            writer,
+            connection: 0,
+            reader: None,
+            user_id: id,
+            resume_token,
+            client_rx: rec,
+            player_manager,
+            clock: ClockSync::new(),
+            drift: DriftController::new(config.drift),
+            local_media,
+            profile: None,
+            hub_state: context.hub_state,
+            peer_sync: context.peer_sync,
+            closed: context.closed,
+            ticks: 0,
+            resyncing: false,
+            playback: None,
+            next_request: 0,
+            pending: HashMap::new(),
+            events: context.events,
+            server: context.server,
+            proxy_tx: context.proxy_tx,
+            hello_profile: config.profile,
+            reconnect: config.reconnect,
+        })
+    }
+
+    pub fn clock(&self) -> &ClockSync {
+        &self.clock
+    }
+
+    async fn handle_server_message(&mut self, message: Output) -> Res {
         match message {
-            Output::Connected(_) => { todo!("Bizarre") }
-            Output::Joined => { todo!() }
-            Output::Timestamp(timestamp) => {
-                trace!("timestamp: received {}", timestamp);
+            Output::Connected { .. } => {
+                warn!("Handshake message received after the handshake");
+                OK
+            }
+            Output::Profile(profile) => {
+                info!("Connected as {}", profile.pseudo);
+                self.emit(ClientEvent::ProfileUpdated(profile.clone()));
+                self.profile = Some(profile);
+                OK
+            }
+            Output::Joined(session_id) => {
+                self.joined(session_id);
                 OK
             }
-            Output::World(_) => { todo!() }
+            Output::PeerLeft(peer_id) if peer_id == self.user_id => {
+                info!("Left the session");
+                self.playback = None;
+                if self.player_manager.state() == PlayerState::Playing {
+                    self.player_manager.pause()?;
+                }
+                OK
+            }
+            Output::PeerLeft(peer_id) => {
+                info!("{} left the session", peer_id);
+                self.peer_sync.write().unwrap().remove(&peer_id);
+                self.emit(ClientEvent::PeerLeft(peer_id));
+                OK
+            }
+            Output::Permissions { owner, policy, controllers } => {
+                info!("Session owned by {}, control: {:?}, controllers: {:?}", owner, policy, controllers);
+                self.emit(ClientEvent::Permissions { owner, policy, controllers });
+                OK
+            }
+            Output::Timestamp { position, server_time, playing } => {
+                trace!("timestamp: received {} sampled at {} (local clock)", position, self.clock.to_local(server_time));
+                let playback = SessionPlayback { position: Duration::from_millis(position), server_time, playing };
+                self.playback = Some(playback);
+                self.correct_drift(playback)
+            }
+            Output::CatchUp { media, position, server_time, playing } => {
+                let playback = SessionPlayback { position: Duration::from_millis(position), server_time, playing };
+                self.playback = Some(playback);
+                self.catch_up(media, playback)
+            }
+            Output::Pong { client_sent, server_received, server_sent } => {
+                let sample = self.clock.add_pong(client_sent, server_received, server_sent);
+                trace!("Clock sample: {:?}, retained offset: {}µs", sample, self.clock.offset());
+                OK
+            }
+            Output::World(state) => {
+                debug!("Hub state {}: {} sessions, {} peers connected", state.seq, state.sessions.len(), state.connected.len());
+                let previous_session = self.my_session_id();
+                *self.hub_state.write().unwrap() = Some(state);
+                self.resyncing = false;
+                self.hub_state_updated(previous_session);
+                OK
+            }
+            Output::HubDelta { seq, delta } => {
+                self.apply_delta(seq, delta).await
+            }
             Output::Error(e) => {
                 self.handle_error(e);
                 OK
             }
-            Output::PlayerAction(action) => {
+            Output::Ack(request, ack) => {
+                self.answer(request, Ok(ack));
+                OK
+            }
+            Output::Refused(request, e) => {
+                self.answer(request, Err(e.into()));
+                OK
+            }
+            Output::PlayerAction(from, action) => {
+                debug!("Action {:?} initiated by {}", action, from);
                 match action {
                     PlayerAction::Play => self.player_manager.play(),
                     PlayerAction::Pause => self.player_manager.pause(),
                     PlayerAction::Stop => self.player_manager.stop(),
+                    PlayerAction::Seek(position) => {
+                        self.reset_rate()?;
+                        self.player_manager.seek(Duration::from_millis(position))
+                    }
+                }?;
+                self.emit(ClientEvent::Playback(from, action));
+                OK
+            }
+            Output::PeerSync(peer_id, report) => {
+                self.peer_sync.write().unwrap().insert(peer_id, report);
+                OK
+            }
+        }
+    }
+
+
+    /// Applies the delta on the cached hub state, a full state is requested when a delta is missing.
+    async fn apply_delta(&mut self, seq: u64, delta: HubDelta) -> Res {
+        // Deltas are dropped until the full state is received
+        if self.resyncing {
+            return OK;
+        }
+        let previous_session = self.my_session_id();
+        trace!("Hub delta {}: {:?}", seq, delta);
+        let outcome = self.hub_state.write().unwrap().as_mut()
+            .map(|state| (state.seq, state.apply_numbered(seq, delta)));
+        match outcome {
+            Some((_, DeltaOutcome::Applied)) => self.hub_state_updated(previous_session),
+            Some((last, DeltaOutcome::Gap)) => {
+                warn!("Hub deltas {} to {} missed, asking for the full state", last + 1, seq - 1);
+                self.resyncing = true;
+                self.send_hub_action(HubAction::Resync).await?;
+            }
+            // Outdated or received before the first full state
+            _ => ignore(),
+        }
+        OK
+    }
+
+    /// Forwards to the session what the user did in the player, the commanded changes are already
+    /// filtered out by the player.
+    async fn handle_player_event(&mut self, event: PlayerEvent) -> Res {
+        match event {
+            PlayerEvent::UserAction(action) => {
+                info!("Player action {:?} sent to the session", action);
+                if let PlayerAction::Seek(_) = action {
+                    self.reset_rate()?;
                 }
+                self.send_request(InputAction::SessionAction(action), Pending::UserAction(action)).await
+            }
+            event => {
+                trace!("Player event {:?}", event);
+                OK
             }
         }
     }
 
+    /// Session position extrapolated to now.
+    fn expected_position(&self, playback: SessionPlayback) -> Duration {
+        if playback.playing {
+            playback.position + Duration::from_micros(self.clock.server_now().saturating_sub(playback.server_time))
+        } else {
+            playback.position
+        }
+    }
 
-    pub async fn join_session(&mut self) -> Res {
-        unimplemented!()
+    /// Puts the local player in the state of the session just joined.
+    fn catch_up(&mut self, media: String, playback: SessionPlayback) -> Res {
+        info!("Catching up the session at {:?}, playing: {}", playback.position, playback.playing);
+        if !self.local_media && !media.is_empty() {
+            self.player_manager.load(media)?;
+        }
+        self.set_playback(playback)
     }
 
-    pub async fn create_session(&mut self, password: String) -> Res<()> {
-        self.writer.send(Input { from: Some(self.user_id), action: InputAction::HubAction(HubAction::CreateSession(password)) }).await?;
-        Ok(())
+    /// Seeks the local player to the session position and plays or pauses it like the session.
+    fn set_playback(&mut self, playback: SessionPlayback) -> Res {
+        self.reset_rate()?;
+        self.player_manager.seek(self.expected_position(playback))?;
+        match (playback.playing, self.player_manager.state()) {
+            (true, PlayerState::Paused) => self.player_manager.play(),
+            (false, PlayerState::Playing) => self.player_manager.pause(),
+            _ => OK,
+        }
     }
 
-    pub async fn start(&mut self) -> Res<()> {
-        self.writer.send(Input { from: Some(self.user_id), action: InputAction::HubAction(HubAction::SessionStart) }).await?;
-        Ok(())
+    /// Sets the player back to the session playback, the server refused what the user did in it.
+    fn user_action_refused(&mut self, action: PlayerAction, error: anyhow::Error) {
+        warn!("Player action {:?} refused: {}", action, error);
+        if let Some(playback) = self.playback {
+            if let Err(e) = self.set_playback(playback) {
+                warn!("Couldn't set the player back to the session playback: {}", e);
+            }
+        }
+        if let Some(error) = error.downcast_ref::<OutputError>() {
+            self.emit(ClientEvent::ActionRefused(action, *error));
+        }
     }
 
-    pub async fn pause(&mut self) -> Res<()> {
-        self.writer.send(Input { from: Some(self.user_id), action: InputAction::SessionAction(PlayerAction::Pause) }).await?;
-        Ok(())
+    /// Brings the local player back to the session position, extrapolated to now in the server clock.
+    fn correct_drift(&mut self, playback: SessionPlayback) -> Res {
+        let expected = self.expected_position(playback);
+        match self.drift.update(self.player_manager.position(), expected, playback.playing) {
+            Correction::None => OK,
+            Correction::Rate(rate) => {
+                debug!("Drift of {}ms, playback rate set to {}", self.drift.stats().last_drift, rate);
+                self.player_manager.set_rate(rate)
+            }
+            Correction::Seek { position, reset_rate } => {
+                debug!("Drift of {}ms, seeking to {:?}", self.drift.stats().last_drift, position);
+                if reset_rate {
+                    self.player_manager.set_rate(1.0)?;
+                }
+                self.player_manager.seek(position)
+            }
+        }
     }
 
-    pub async fn play(&mut self) -> Res<()> {
-        self.writer.send(Input { from: Some(self.user_id), action: InputAction::SessionAction(PlayerAction::Play) }).await?;
-        Ok(())
+    /// Sets the player back to its normal rate after a correction done outside the drift controller.
+    fn reset_rate(&mut self) -> Res {
+        if self.drift.reset_rate() {
+            self.player_manager.set_rate(1.0)?;
+        }
+        OK
+    }
+
+    pub async fn join_session(&mut self, session_id: SessionId, password: String, answer: Sender<Res>) -> Res {
+        let action = InputAction::HubAction(HubAction::Join(session_id, password));
+        self.send_request(action, Pending::Join(session_id, Some(answer))).await
     }
 
-    pub async fn stop(&mut self) -> Res<()> {
-        unimplemented!()
+    pub async fn request(&mut self, action: InputAction, answer: Sender<Res<Ack>>) -> Res {
+        self.send_request(action, Pending::Request(answer)).await
+    }
+
+    async fn send_request(&mut self, action: InputAction, pending: Pending) -> Res {
+        self.next_request += 1;
+        let request = self.next_request;
+        // Abandoned with the others if the connection is lost
+        self.pending.insert(request, pending);
+        self.send(Input::request(request, action)).await
+    }
+
+    fn answer(&mut self, request: RequestId, result: Res<Ack>) {
+        let sent = match self.pending.remove(&request) {
+            Some(Pending::Request(answer)) => answer.send(result).is_ok(),
+            Some(Pending::Join(_, Some(answer))) => answer.send(result.map(|_| ())).is_ok(),
+            // Already answered by the joined
+            Some(Pending::Join(_, None)) => true,
+            Some(Pending::UserAction(action)) => {
+                if let Err(e) = result {
+                    self.user_action_refused(action, e);
+                }
+                true
+            }
+            None => return warn!("Answer to the unknown request {}", request),
+        };
+        if !sent {
+            debug!("The request {} was abandoned", request);
+        }
+    }
+
+    fn joined(&mut self, session_id: SessionId) {
+        info!("Joined the session {}", session_id);
+        let answer = self.pending.values_mut().find_map(|pending| match pending {
+            Pending::Join(joining, answer) if *joining == session_id => answer.take(),
+            _ => None,
+        });
+        if let Some(answer) = answer {
+            answer.send(OK).ignore();
+        }
+    }
+
+    /// Reconnects with an exponential backoff, taking over the peer of the lost connection so its
+    /// session goes on. Fails when every attempt failed.
+    async fn reconnect(&mut self) -> Res {
+        self.abandon_requests();
+        let mut delay = self.reconnect.initial_delay;
+        for attempt in 1..=self.reconnect.max_attempts {
+            self.emit(ClientEvent::Reconnecting(attempt));
+            select! {
+                _ = sleep(delay) => {}
+                // The proxy stops at its next message
+                _ = self.closed.recv_async() => return OK,
+            }
+            let resume = Resume { peer: self.user_id, token: self.resume_token };
+            match connect(&self.server, &self.hello_profile, Some(resume)).await {
+                Ok(connection) => {
+                    self.resume(connection);
+                    return OK;
+                }
+                Err(e) => warn!("Reconnection attempt {} failed: {}", attempt, e),
+            }
+            delay = (delay * 2).min(self.reconnect.max_delay);
+        }
+        self.emit(ClientEvent::Disconnected);
+        bail!("Disconnected from the server")
+    }
+
+    /// Stops the reader and the player, the connection is closed when the proxy is dropped.
+    async fn close(&mut self) -> Res {
+        if let Some(reader) = self.reader.take() {
+            reader.abort();
+        }
+        self.abandon_requests();
+        if let Some(player) = self.player_manager.shutdown() {
+            tokio::task::spawn_blocking(move || player.join()).await?
+                .map_err(|_| anyhow!("The player panicked"))?;
+        }
+        OK
+    }
+
+    fn resume(&mut self, connection: Connection) {
+        if connection.resumed {
+            info!("Connection resumed");
+        } else {
+            warn!("The server didn't resume the connection, connected as the new peer {}", connection.id);
+        }
+        self.user_id = connection.id;
+        self.resume_token = connection.resume_token;
+        self.writer = connection.writer;
+        // A full hub state follows the connection
+        self.resyncing = false;
+        self.listen(connection.reader);
+        self.emit(ClientEvent::Reconnected { id: connection.id, resumed: connection.resumed });
+    }
+
+    /// Reads the new connection, the reader of the previous one is stopped.
+    fn listen(&mut self, reader: NetReader<Output, OwnedReadHalf>) {
+        if let Some(previous) = self.reader.take() {
+            previous.abort();
+        }
+        self.connection += 1;
+        self.reader = Some(spawn_reader(reader, self.proxy_tx.clone(), self.connection));
+    }
+
+    /// Sends the input to the server, a failure is handled as the loss of the connection.
+    async fn send(&mut self, input: Input) -> Res {
+        if let Err(e) = self.writer.send(input).await {
+            self.proxy_tx.send_async(ProxyMessage::Disconnected(self.connection)).await.ignore();
+            bail!("Couldn't send to the server: {}", e);
+        }
+        OK
+    }
+
+    /// Fails the requests sent on a lost connection, their answer will never come.
+    fn abandon_requests(&mut self) {
+        for (_, pending) in self.pending.drain() {
+            let lost = || anyhow!("The connection to the server was lost");
+            match pending {
+                Pending::Request(answer) => answer.send(Err(lost())).ignore(),
+                Pending::Join(_, Some(answer)) => answer.send(Err(lost())).ignore(),
+                // The session catches the player up on the resume
+                Pending::Join(_, None) | Pending::UserAction(_) => ignore(),
+            }
+        }
+    }
+
+    pub async fn send_hub_action(&mut self, action: HubAction) -> Res<()> {
+        self.send(Input::new(InputAction::HubAction(action))).await
     }
 
     pub async fn alive(&mut self) -> Res<()> {
-        self.writer.send(Input { from: Some(self.user_id), action: InputAction::Alive }).await?;
+        self.send(Input::new(InputAction::Ping(now_micros()))).await?;
+        self.ticks += 1;
+        if self.ticks.is_multiple_of(REPORT_TICKS) && self.my_session_id().is_some() {
+            self.send_report().await?;
+        }
         Ok(())
     }
+
+    async fn send_report(&mut self) -> Res {
+        let report = SyncReport {
+            position: self.player_manager.position().as_millis() as u64,
+            drift: self.drift.stats().last_drift,
+            playing: self.player_manager.state() == PlayerState::Playing,
+        };
+        self.send(Input::new(InputAction::SyncReport(report))).await
+    }
 }
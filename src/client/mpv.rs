@@ -0,0 +1,369 @@
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use log::*;
+use serde_json::{json, Value};
+
+use crate::client::player::{PlayerBackend, PlayerEvent, PlayerState};
+use crate::server::{Res, OK};
+
+const SOCKET_WAIT: Duration = Duration::from_secs(5);
+const TIME_POS_OBSERVER: u64 = 1;
+const PAUSE_OBSERVER: u64 = 2;
+
+#[derive(Clone, Debug)]
+pub struct MpvConfig {
+    /// Path of the `--input-ipc-server` socket.
+    pub socket: PathBuf,
+    /// Spawns mpv on the socket, otherwise attaches to an already running instance.
+    pub spawn: bool,
+    /// Spawns mpv without video and audio outputs.
+    pub headless: bool,
+}
+
+/// Backend driving mpv through its JSON IPC.
+///
+/// Commands are written without waiting for their answer, the position and the pause state are
+/// observed properties read back at each poll.
+pub struct MpvPlayer {
+    process: Option<Child>,
+    stream: UnixStream,
+    pending: Vec<u8>,
+    request_id: u64,
+    /// Request of the position read after a playback restart, the observed one may be older.
+    restart_request: Option<u64>,
+    position: Duration,
+    paused: bool,
+    stopping: bool,
+}
+
+impl MpvPlayer {
+    pub fn new(config: &MpvConfig) -> Res<Self> {
+        if config.spawn {
+            Self::spawn(&config.socket, config.headless)
+        } else {
+            Self::attach(&config.socket)
+        }
+    }
+
+    pub fn spawn(socket: &Path, headless: bool) -> Res<Self> {
+        let mut command = Command::new("mpv");
+        command.arg("--idle=yes")
+            .arg("--pause")
+            .arg(format!("--input-ipc-server={}", socket.display()))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        if headless {
+            command.args(["--vo=null", "--ao=null"]);
+        }
+        let process = command.spawn().context("Couldn't spawn mpv")?;
+
+        let start = Instant::now();
+        let stream = loop {
+            match UnixStream::connect(socket) {
+                Ok(stream) => break stream,
+                Err(e) if start.elapsed() > SOCKET_WAIT => return Err(e).context("mpv didn't open its ipc socket"),
+                Err(_) => thread::sleep(Duration::from_millis(50)),
+            }
+        };
+        let mut player = Self::with_stream(stream)?;
+        player.process = Some(process);
+        Ok(player)
+    }
+
+    pub fn attach(socket: &Path) -> Res<Self> {
+        let stream = UnixStream::connect(socket)
+            .with_context(|| format!("Couldn't connect to the mpv socket {}", socket.display()))?;
+        Self::with_stream(stream)
+    }
+
+    pub fn with_stream(stream: UnixStream) -> Res<Self> {
+        stream.set_nonblocking(true)?;
+        let mut player = MpvPlayer {
+            process: None,
+            stream,
+            pending: Vec::new(),
+            request_id: 0,
+            restart_request: None,
+            position: Duration::from_millis(0),
+            paused: true,
+            stopping: false,
+        };
+        player.command(json!(["observe_property", TIME_POS_OBSERVER, "time-pos"]))?;
+        player.command(json!(["observe_property", PAUSE_OBSERVER, "pause"]))?;
+        Ok(player)
+    }
+
+    fn command(&mut self, command: Value) -> Res {
+        self.request_id += 1;
+        let mut line = serde_json::to_vec(&json!({ "command": command, "request_id": self.request_id }))?;
+        line.push(b'\n');
+        // The socket is non blocking, a full buffer only delays the write
+        let mut written = 0;
+        while written < line.len() {
+            match self.stream.write(&line[written..]) {
+                Ok(0) => bail!("mpv closed its ipc socket"),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        trace!("mpv command sent {:?}", command);
+        OK
+    }
+
+    /// Reads every complete line available on the socket.
+    fn read_lines(&mut self) -> Res<Vec<Value>> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => bail!("mpv closed its ipc socket"),
+                Ok(n) => self.pending.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut lines = Vec::new();
+        while let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            match serde_json::from_slice(&line) {
+                Ok(value) => lines.push(value),
+                Err(e) => warn!("Invalid message from mpv: {}", e),
+            }
+        }
+        Ok(lines)
+    }
+
+    fn handle_message(&mut self, message: &Value) -> Option<PlayerEvent> {
+        let request_id = message.get("request_id").and_then(Value::as_u64);
+        if request_id.is_some() && request_id == self.restart_request {
+            self.restart_request = None;
+            if let Some(position) = message.get("data").and_then(Value::as_f64) {
+                self.position = Duration::from_secs_f64(position.max(0.0));
+            }
+            return Some(PlayerEvent::Seeked(self.position));
+        }
+        if let Some(error) = message.get("error").and_then(Value::as_str) {
+            if error != "success" {
+                warn!("mpv answered {} to the request {}", error, message["request_id"]);
+            }
+            return None;
+        }
+        match message.get("event").and_then(Value::as_str)? {
+            "property-change" => {
+                match message.get("id").and_then(Value::as_u64)? {
+                    TIME_POS_OBSERVER => {
+                        let position = message.get("data").and_then(Value::as_f64).unwrap_or(0.0);
+                        self.position = Duration::from_secs_f64(position.max(0.0));
+                    }
+                    PAUSE_OBSERVER => {
+                        self.paused = message.get("data").and_then(Value::as_bool).unwrap_or(true);
+                    }
+                    _ => {}
+                }
+                None
+            }
+            "playback-restart" => {
+                match self.command(json!(["get_property", "time-pos"])) {
+                    Ok(()) => {
+                        self.restart_request = Some(self.request_id);
+                        None
+                    }
+                    Err(e) => {
+                        warn!("Couldn't read the position after a restart: {}", e);
+                        Some(PlayerEvent::Seeked(self.position))
+                    }
+                }
+            }
+            "end-file" if message.get("reason").and_then(Value::as_str) == Some("eof") => {
+                Some(PlayerEvent::EndReached)
+            }
+            "shutdown" => {
+                self.stopping = true;
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+impl PlayerBackend for MpvPlayer {
+    fn load(&mut self, media: &str) -> Res {
+        self.command(json!(["loadfile", media, "replace"]))
+    }
+
+    fn play(&mut self) -> Res {
+        self.command(json!(["set_property", "pause", false]))
+    }
+
+    fn pause(&mut self) -> Res {
+        self.command(json!(["set_property", "pause", true]))
+    }
+
+    fn stop(&mut self) -> Res {
+        self.stopping = true;
+        if self.process.is_some() {
+            self.command(json!(["quit"]))
+        } else {
+            self.command(json!(["stop"]))
+        }
+    }
+
+    fn seek(&mut self, position: Duration) -> Res {
+        self.command(json!(["seek", position.as_secs_f64(), "absolute"]))
+    }
+
+    fn set_rate(&mut self, rate: f64) -> Res {
+        self.command(json!(["set_property", "speed", rate]))
+    }
+
+    fn position(&self) -> Duration {
+        self.position
+    }
+
+    fn state(&self) -> PlayerState {
+        if self.stopping {
+            PlayerState::Stopping
+        } else if self.paused {
+            PlayerState::Paused
+        } else {
+            PlayerState::Playing
+        }
+    }
+
+    fn poll(&mut self) -> Vec<PlayerEvent> {
+        match self.read_lines() {
+            Ok(messages) => messages.iter().filter_map(|m| self.handle_message(m)).collect(),
+            Err(e) => {
+                error!("Lost mpv: {}", e);
+                self.stopping = true;
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl Drop for MpvPlayer {
+    fn drop(&mut self) {
+        if let Some(mut process) = self.process.take() {
+            if let Err(e) = process.kill().and_then(|_| process.wait()) {
+                debug!("mpv already exited: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+
+    use super::*;
+
+    /// The other end of the ipc socket, in place of mpv.
+    struct FakeMpv {
+        reader: BufReader<UnixStream>,
+        writer: UnixStream,
+    }
+
+    impl FakeMpv {
+        fn connect() -> (MpvPlayer, FakeMpv) {
+            let (player_end, fake_end) = UnixStream::pair().unwrap();
+            fake_end.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            let player = MpvPlayer::with_stream(player_end).unwrap();
+            let fake = FakeMpv { reader: BufReader::new(fake_end.try_clone().unwrap()), writer: fake_end };
+            (player, fake)
+        }
+
+        fn next_message(&mut self) -> Value {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        fn next_command(&mut self) -> Value {
+            self.next_message()["command"].clone()
+        }
+
+        fn send(&mut self, message: Value) {
+            writeln!(self.writer, "{}", message).unwrap();
+        }
+    }
+
+    #[test]
+    fn observes_the_position_and_the_pause() {
+        let (_player, mut mpv) = FakeMpv::connect();
+        assert_eq!(mpv.next_command(), json!(["observe_property", TIME_POS_OBSERVER, "time-pos"]));
+        assert_eq!(mpv.next_command(), json!(["observe_property", PAUSE_OBSERVER, "pause"]));
+    }
+
+    #[test]
+    fn sends_the_playback_commands() {
+        let (mut player, mut mpv) = FakeMpv::connect();
+        mpv.next_command();
+        mpv.next_command();
+
+        player.play().unwrap();
+        assert_eq!(mpv.next_command(), json!(["set_property", "pause", false]));
+        player.pause().unwrap();
+        assert_eq!(mpv.next_command(), json!(["set_property", "pause", true]));
+        player.seek(Duration::from_millis(12500)).unwrap();
+        assert_eq!(mpv.next_command(), json!(["seek", 12.5, "absolute"]));
+        player.set_rate(1.05).unwrap();
+        assert_eq!(mpv.next_command(), json!(["set_property", "speed", 1.05]));
+        player.load("/tmp/media.mkv").unwrap();
+        assert_eq!(mpv.next_command(), json!(["loadfile", "/tmp/media.mkv", "replace"]));
+    }
+
+    #[test]
+    fn reads_the_observed_properties() {
+        let (mut player, mut mpv) = FakeMpv::connect();
+        assert_eq!(player.state(), PlayerState::Paused);
+
+        mpv.send(json!({ "event": "property-change", "id": TIME_POS_OBSERVER, "name": "time-pos", "data": 3.25 }));
+        mpv.send(json!({ "event": "property-change", "id": PAUSE_OBSERVER, "name": "pause", "data": false }));
+        assert_eq!(player.poll(), vec![]);
+        assert_eq!(player.position(), Duration::from_millis(3250));
+        assert_eq!(player.state(), PlayerState::Playing);
+    }
+
+    #[test]
+    fn reports_a_restart_with_the_position_read_after_it() {
+        let (mut player, mut mpv) = FakeMpv::connect();
+        mpv.next_command();
+        mpv.next_command();
+        mpv.send(json!({ "event": "property-change", "id": TIME_POS_OBSERVER, "name": "time-pos", "data": 1.0 }));
+        mpv.send(json!({ "event": "playback-restart" }));
+        assert_eq!(player.poll(), vec![]);
+
+        let request = mpv.next_message();
+        assert_eq!(request["command"], json!(["get_property", "time-pos"]));
+        mpv.send(json!({ "error": "success", "data": 42.5, "request_id": request["request_id"] }));
+        assert_eq!(player.poll(), vec![PlayerEvent::Seeked(Duration::from_millis(42500))]);
+        assert_eq!(player.position(), Duration::from_millis(42500));
+    }
+
+    #[test]
+    fn reports_the_end_of_the_media_and_the_shutdown() {
+        let (mut player, mut mpv) = FakeMpv::connect();
+        mpv.send(json!({ "event": "end-file", "reason": "stop" }));
+        mpv.send(json!({ "event": "end-file", "reason": "eof" }));
+        assert_eq!(player.poll(), vec![PlayerEvent::EndReached]);
+
+        mpv.send(json!({ "event": "shutdown" }));
+        assert_eq!(player.poll(), vec![]);
+        assert_eq!(player.state(), PlayerState::Stopping);
+    }
+
+    #[test]
+    fn stops_when_mpv_closes_its_socket() {
+        let (mut player, mpv) = FakeMpv::connect();
+        drop(mpv);
+        assert_eq!(player.poll(), vec![]);
+        assert_eq!(player.state(), PlayerState::Stopping);
+    }
+}
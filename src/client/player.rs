@@ -1,120 +1,438 @@
-use flume::{Receiver,  Sender, bounded};
+use flume::{Receiver,  Sender, SendError, RecvTimeoutError, bounded};
 use crate::server::{Res, OK};
+use crate::server::net_proto::PlayerAction;
 use anyhow::*;
 use crate::client::player::PlayerState::{Playing, Paused};
-use crate::{ignore, Ignore};
+use crate::Builder;
 use log::*;
+use std::fmt;
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 use std::thread;
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "vlc")]
+use crate::client::vlc::VlcPlayer;
+#[cfg(unix)]
+use crate::client::mpv::{MpvConfig, MpvPlayer};
+
+const EVENTS_CAPACITY: usize = 64;
+/// Time after which a command that didn't produce its event is no more expected.
+const ECHO_EXPIRY: Duration = Duration::from_millis(1000);
 
 #[derive(Debug)]
 pub enum PlayerMessage {
+    Load(String),
     Play,
     Pause,
     Stop,
+    Seek(Duration),
+    SetRate(f64),
 }
 
-#[derive(Debug, Eq, PartialEq)]
-enum PlayerState {
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PlayerState {
     Paused,
     Playing,
     Stopping,
 }
 
+/// What happened in a backend, sent on the stream returned by [`PlayerManager::events`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlayerEvent {
+    StateChanged(PlayerState),
+    Seeked(Duration),
+    EndReached,
+    /// Action done by the user in the player itself, it was not commanded by the manager.
+    UserAction(PlayerAction),
+}
+
+/// A media player the [`PlayerManager`] can drive.
+///
+/// A backend lives on the player thread and is created there, so it doesn't need to be `Send`.
+pub trait PlayerBackend {
+    fn load(&mut self, media: &str) -> Res;
+
+    fn play(&mut self) -> Res;
+
+    fn pause(&mut self) -> Res;
+
+    fn stop(&mut self) -> Res;
+
+    fn seek(&mut self, position: Duration) -> Res;
+
+    fn set_rate(&mut self, rate: f64) -> Res;
+
+    fn position(&self) -> Duration;
+
+    fn state(&self) -> PlayerState;
+
+    /// Called at each iteration of the player thread, returns the events since the last call.
+    /// The state changes are detected by the manager and don't need to be returned.
+    fn poll(&mut self) -> Vec<PlayerEvent> {
+        Vec::new()
+    }
+}
+
+/// What the player thread reports of itself, updated at each of its iterations.
+#[derive(Clone, Copy, Debug)]
+pub struct PlayerReport {
+    pub position: Duration,
+    pub state: PlayerState,
+}
+
+impl Default for PlayerReport {
+    fn default() -> Self {
+        PlayerReport {
+            position: Duration::from_millis(0),
+            state: PlayerState::Paused,
+        }
+    }
+}
+
+pub type SharedReport = Arc<RwLock<PlayerReport>>;
+
+pub type BackendFactory = Arc<dyn Fn() -> Res<Box<dyn PlayerBackend>> + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub enum PlayerKind {
+    /// Plays nothing, only moves a needle on a 30 seconds media.
+    #[default]
+    Simulated,
+    /// Plays the media with libvlc, without any video or audio output when headless.
+    #[cfg(feature = "vlc")]
+    Vlc { headless: bool },
+    /// Drives mpv through its JSON IPC socket.
+    #[cfg(unix)]
+    Mpv(MpvConfig),
+    /// Any other backend, built on the player thread.
+    Custom(BackendFactory),
+}
+
+impl fmt::Debug for PlayerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayerKind::Simulated => write!(f, "Simulated"),
+            #[cfg(feature = "vlc")]
+            PlayerKind::Vlc { headless } => write!(f, "Vlc {{ headless: {} }}", headless),
+            #[cfg(unix)]
+            PlayerKind::Mpv(config) => write!(f, "Mpv({:?})", config),
+            PlayerKind::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl PlayerKind {
+    pub fn build(&self) -> Res<Box<dyn PlayerBackend>> {
+        match self {
+            PlayerKind::Simulated => Ok(SimulatedPlayer::new().boxed()),
+            #[cfg(feature = "vlc")]
+            PlayerKind::Vlc { headless } => Ok(VlcPlayer::new(*headless)?.boxed()),
+            #[cfg(unix)]
+            PlayerKind::Mpv(config) => Ok(MpvPlayer::new(config)?.boxed()),
+            PlayerKind::Custom(factory) => factory(),
+        }
+    }
+}
+
 pub struct PlayerManager {
+    /// Kept to restart the player thread, it ends when the player stops.
+    kind: Option<PlayerKind>,
+    /// Last media loaded, loaded again by a restarted player.
+    media: Option<String>,
     sender_player: Option<Sender<PlayerMessage>>,
     player_handle: Option<JoinHandle<()>>,
-    //TODO media etc..
+    report: SharedReport,
+    events: Receiver<PlayerEvent>,
+    events_tx: Sender<PlayerEvent>,
+}
+
+impl Default for PlayerManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PlayerManager {
     pub fn new() -> Self {
+        let (events_tx, events) = bounded(EVENTS_CAPACITY);
         Self {
+            kind: None,
+            media: None,
             sender_player: None,
-            player_handle: None
+            player_handle: None,
+            report: PlayerReport::default().rw_lock().arc(),
+            events,
+            events_tx,
         }
     }
-    
-    pub fn start(&mut self) {
+
+    pub fn start(&mut self, kind: PlayerKind) {
+        self.kind = Some(kind.clone());
         let (tx, rx) = bounded(8);
         self.sender_player = Some(tx);
-        self.player_handle = Some(thread::spawn(|| {
-            Player {
-                proxy_receiver: rx,
-                state: PlayerState::Paused,
-            }.run();
+        let report = self.report.clone();
+        let events = self.events_tx.clone();
+        self.player_handle = Some(thread::spawn(move || {
+            match kind.build() {
+                Result::Ok(backend) => Player {
+                    proxy_receiver: rx,
+                    backend,
+                    report,
+                    events,
+                    echo: EchoFilter::default(),
+                }.run(),
+                Err(e) => error!("Couldn't start the player {:?}: {}", kind, e),
+            }
         }
         ));
     }
 
+    /// Stops the player thread, returned to wait for the backend to be closed. The manager can't be
+    /// used after.
+    pub fn shutdown(&mut self) -> Option<JoinHandle<()>> {
+        self.kind = None;
+        self.sender_player = None;
+        self.player_handle.take()
+    }
+
     fn sender(&self) -> &Sender<PlayerMessage> {
         self.sender_player.as_ref().expect("Player not started")
     }
 
-    pub fn play(&self) -> Res {
-        self.sender().send(PlayerMessage::Play).context("Couldn't send the play message to the player")
+    /// Sends the message to the player thread, restarted with the last media if it ended, e.g.
+    /// after a stop.
+    fn send(&mut self, message: PlayerMessage) -> Res {
+        let message = match self.sender().send(message) {
+            Result::Ok(()) => return OK,
+            Err(SendError(message)) => message,
+        };
+        let kind = self.kind.clone().context("Player not started")?;
+        info!("The player thread ended, restarting it");
+        self.start(kind);
+        if let Some(media) = self.media.clone() {
+            self.sender().send(PlayerMessage::Load(media)).context("Couldn't restart the player")?;
+        }
+        self.sender().send(message).context("Couldn't restart the player")
+    }
+
+    pub fn load(&mut self, media: String) -> Res {
+        self.media = Some(media.clone());
+        self.send(PlayerMessage::Load(media)).context("Couldn't send the media to the player")
     }
 
-    pub fn stop(&self) -> Res {
-        self.sender().send(PlayerMessage::Pause)
+    pub fn play(&mut self) -> Res {
+        self.send(PlayerMessage::Play).context("Couldn't send the play message to the player")
+    }
+
+    pub fn stop(&mut self) -> Res {
+        self.send(PlayerMessage::Stop)
             .context("Couldn't send the stop message to the player")
     }
 
-    pub fn pause(&self) -> Res {
-        self.sender().send(PlayerMessage::Stop)
+    pub fn pause(&mut self) -> Res {
+        self.send(PlayerMessage::Pause)
             .context("Couldn't send pause message to the player")
     }
+
+    pub fn seek(&mut self, position: Duration) -> Res {
+        self.send(PlayerMessage::Seek(position))
+            .context("Couldn't send seek message to the player")
+    }
+
+    pub fn set_rate(&mut self, rate: f64) -> Res {
+        self.send(PlayerMessage::SetRate(rate))
+            .context("Couldn't send rate message to the player")
+    }
+
+    /// Last position reported by the player.
+    pub fn position(&self) -> Duration {
+        self.report.read().unwrap().position
+    }
+
+    pub fn state(&self) -> PlayerState {
+        self.report.read().unwrap().state
+    }
+
+    /// Report of the player thread, updated at each of its iterations.
+    pub fn report(&self) -> SharedReport {
+        self.report.clone()
+    }
+
+    /// Stream of the backend events, events are dropped when nobody consumes them.
+    pub fn events(&self) -> Receiver<PlayerEvent> {
+        self.events.clone()
+    }
+}
+
+/// Remembers the effects expected from the commands sent to the backend, to tell them apart from
+/// the user actions done in the player.
+#[derive(Debug, Default)]
+struct EchoFilter {
+    state: Option<(PlayerState, Instant)>,
+    seeks: Vec<Instant>,
+}
+
+impl EchoFilter {
+    fn expect_state(&mut self, state: PlayerState) {
+        self.state = Some((state, Instant::now()));
+    }
+
+    fn expect_seek(&mut self) {
+        self.seeks.push(Instant::now());
+    }
+
+    fn expire(&mut self) {
+        if matches!(self.state, Some((_, at)) if at.elapsed() > ECHO_EXPIRY) {
+            self.state = None;
+        }
+        self.seeks.retain(|at| at.elapsed() <= ECHO_EXPIRY);
+    }
+
+    /// Returns true if the state change was commanded.
+    fn state_changed(&mut self, state: PlayerState) -> bool {
+        match self.state {
+            Some((expected, _)) if expected == state => {
+                self.state = None;
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// Returns true if the seek was commanded.
+    fn seeked(&mut self) -> bool {
+        if self.seeks.is_empty() {
+            false
+        } else {
+            self.seeks.remove(0);
+            true
+        }
+    }
 }
 
-pub struct Player {
+/// Thread executing the messages of the manager on a backend.
+struct Player {
     proxy_receiver: Receiver<PlayerMessage>,
-    state: PlayerState,
+    backend: Box<dyn PlayerBackend>,
+    report: SharedReport,
+    events: Sender<PlayerEvent>,
+    echo: EchoFilter,
 }
 
 impl Player {
-    pub fn run(&mut self) {
-        let start = Instant::now();
-        let mut needle = Duration::from_millis(0);
-        let duration = Duration::from_secs(30);
+    fn run(&mut self) {
+        let mut state = self.backend.state();
         loop {
-            // TODO handle error
-            self.proxy_receiver.recv_timeout(Duration::from_millis(10)).map(|message| self.handle(&message).unwrap()).ignore();
-
-            match self.state {
-                PlayerState::Playing  => {
-                    if needle <= duration {
-                        needle = Instant::now().duration_since(start);
-                    }else {
-                        self.pause().unwrap();
-                        info!("Video finished, paused");
+            match self.proxy_receiver.recv_timeout(Duration::from_millis(10)) {
+                Result::Ok(message) => {
+                    if let Err(e) = self.handle(&message) {
+                        warn!("Player couldn't handle {:?}: {}", message, e);
                     }
-                    debug!("needle video {:?}", &needle)
                 }
-                PlayerState::Paused => ignore(), // paused,
-                PlayerState::Stopping => break
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    info!("The player manager is gone, the player stops");
+                    break;
+                }
             }
-        }
-    }
 
-    pub fn handle(&mut self, message: &PlayerMessage) -> Res {
-        match message {
-            PlayerMessage::Play => {
-                self.play();
-                OK
+            self.echo.expire();
+            for event in self.backend.poll() {
+                match event {
+                    PlayerEvent::Seeked(position) if !self.echo.seeked() => {
+                        self.emit(PlayerEvent::UserAction(PlayerAction::Seek(position.as_millis() as u64)));
+                    }
+                    PlayerEvent::EndReached => self.echo.expect_state(PlayerState::Paused),
+                    _ => {}
+                }
+                self.emit(event);
             }
-            PlayerMessage::Pause => {
-                self.pause()
+            let new_state = self.backend.state();
+            if new_state != state {
+                state = new_state;
+                if !self.echo.state_changed(state) {
+                    match state {
+                        PlayerState::Playing => self.emit(PlayerEvent::UserAction(PlayerAction::Play)),
+                        PlayerState::Paused => self.emit(PlayerEvent::UserAction(PlayerAction::Pause)),
+                        PlayerState::Stopping => info!("The player was closed"),
+                    }
+                }
+                self.emit(PlayerEvent::StateChanged(state));
             }
-            PlayerMessage::Stop => {
-                self.stop();
-                OK
+            *self.report.write().unwrap() = PlayerReport { position: self.backend.position(), state };
+            if state == PlayerState::Stopping {
+                break;
             }
         }
     }
 
-    fn play(&mut self) {
+    fn emit(&self, event: PlayerEvent) {
+        if self.events.try_send(event).is_err() {
+            trace!("Player event dropped {:?}", event);
+        }
+    }
+
+    fn handle(&mut self, message: &PlayerMessage) -> Res {
+        match message {
+            PlayerMessage::Play => self.echo.expect_state(PlayerState::Playing),
+            PlayerMessage::Pause => self.echo.expect_state(PlayerState::Paused),
+            PlayerMessage::Stop => self.echo.expect_state(PlayerState::Stopping),
+            // Loading restarts the playback in the backends reporting the restarts as seeks
+            PlayerMessage::Seek(_) | PlayerMessage::Load(_) => self.echo.expect_seek(),
+            PlayerMessage::SetRate(_) => {}
+        }
+        match message {
+            PlayerMessage::Load(media) => self.backend.load(media),
+            PlayerMessage::Play => self.backend.play(),
+            PlayerMessage::Pause => self.backend.pause(),
+            PlayerMessage::Stop => self.backend.stop(),
+            PlayerMessage::Seek(position) => self.backend.seek(*position),
+            PlayerMessage::SetRate(rate) => self.backend.set_rate(*rate),
+        }
+    }
+}
+
+/// Backend playing nothing, it moves a needle on a 30 seconds media.
+pub struct SimulatedPlayer {
+    state: PlayerState,
+    needle: Duration,
+    rate: f64,
+    duration: Duration,
+    last_tick: Instant,
+    /// The needle was moved since the last poll.
+    seeked: bool,
+}
+
+impl Default for SimulatedPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimulatedPlayer {
+    pub fn new() -> Self {
+        SimulatedPlayer {
+            state: PlayerState::Paused,
+            needle: Duration::from_millis(0),
+            rate: 1.0,
+            duration: Duration::from_secs(30),
+            last_tick: Instant::now(),
+            seeked: false,
+        }
+    }
+}
+
+impl PlayerBackend for SimulatedPlayer {
+    fn load(&mut self, media: &str) -> Res {
+        info!("Simulating media {}", media);
+        self.needle = Duration::from_millis(0);
+        self.seeked = true;
+        OK
+    }
+
+    fn play(&mut self) -> Res {
         self.state = Playing;
+        OK
     }
 
     fn pause(&mut self) -> Res {
@@ -123,8 +441,94 @@ impl Player {
         OK
     }
 
-    fn stop(&mut self) {
+    fn stop(&mut self) -> Res {
         self.state = PlayerState::Stopping;
+        OK
+    }
+
+    fn seek(&mut self, position: Duration) -> Res {
+        self.needle = position;
+        self.seeked = true;
+        OK
+    }
+
+    fn set_rate(&mut self, rate: f64) -> Res {
+        self.rate = rate;
+        OK
+    }
+
+    fn position(&self) -> Duration {
+        self.needle
+    }
+
+    fn state(&self) -> PlayerState {
+        self.state
+    }
+
+    fn poll(&mut self) -> Vec<PlayerEvent> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_tick);
+        self.last_tick = now;
+        let mut events = Vec::new();
+        if std::mem::take(&mut self.seeked) {
+            events.push(PlayerEvent::Seeked(self.needle));
+        }
+        if self.state != Playing {
+            return events;
+        }
+        if self.needle <= self.duration {
+            self.needle += elapsed.mul_f64(self.rate);
+            debug!("needle video {:?}", &self.needle);
+        } else {
+            self.state = Paused;
+            info!("Video finished, paused");
+            events.push(PlayerEvent::EndReached);
+        }
+        events
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_the_expected_state_once() {
+        let mut echo = EchoFilter::default();
+        echo.expect_state(Playing);
+        assert!(!echo.state_changed(Paused));
+        assert!(echo.state_changed(Playing));
+        assert!(!echo.state_changed(Playing));
+    }
+
+    #[test]
+    fn filters_one_seek_per_expected_seek() {
+        let mut echo = EchoFilter::default();
+        echo.expect_seek();
+        echo.expect_seek();
+        assert!(echo.seeked());
+        assert!(echo.seeked());
+        assert!(!echo.seeked());
+    }
+
+    #[test]
+    fn simulated_player_reports_its_seeks() {
+        let mut player = SimulatedPlayer::new();
+        player.seek(Duration::from_secs(5)).unwrap();
+        assert_eq!(player.poll(), vec![PlayerEvent::Seeked(Duration::from_secs(5))]);
+        assert_eq!(player.poll(), vec![]);
+    }
+
+    #[test]
+    fn forgets_the_expectations_which_expired() {
+        let mut echo = EchoFilter::default();
+        let expired = Instant::now() - ECHO_EXPIRY * 2;
+        echo.state = Some((Paused, expired));
+        echo.seeks = vec![expired];
+        echo.expect_seek();
+        echo.expire();
+        assert!(!echo.state_changed(Paused));
+        assert!(echo.seeked());
+        assert!(!echo.seeked());
+    }
+}
@@ -0,0 +1,471 @@
+use std::io::{stdout, Stdout};
+use std::thread;
+
+use anyhow::Context;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use flume::Sender;
+use structopt::StructOpt;
+use tokio::select;
+use tokio::time::{interval, Duration};
+use tui::backend::CrosstermBackend;
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, Cell, List, ListItem, ListState, Paragraph, Row, Table};
+use tui::{Frame, Terminal};
+
+use syncplay::client::{create_client_with, Client, ClientConfig};
+use syncplay::client::drift::DriftStats;
+use syncplay::client::event::ClientEvent;
+use syncplay::client::cli::{describe_action, format_position, PlayerOpt};
+use syncplay::client::player::PlayerState;
+use syncplay::server::{PeerId, Res, SessionId, OK};
+use syncplay::server::net_proto::{ControlPolicy, PlayerAction, Profile, SessionDTO};
+
+const REDRAW_TICK: Duration = Duration::from_millis(200);
+const SEEK_STEP: Duration = Duration::from_secs(10);
+const HELP: &str = "↑↓ select  enter join  c create  l leave  s start  space play/pause  ←→ seek  q quit";
+
+type Backend = CrosstermBackend<Stdout>;
+
+/// Full-screen terminal client of a sync_daemon server.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "sync_tui")]
+struct Opt {
+    /// Address of the server
+    #[structopt(short, long, default_value = "127.0.0.1:5135")]
+    server: String,
+    /// Nickname shown to the other peers, chosen by the server when empty
+    #[structopt(short, long, default_value = "")]
+    nickname: String,
+    #[structopt(flatten)]
+    player: PlayerOpt,
+    /// Local path of the media played in the session
+    #[structopt(short, long)]
+    media: Option<String>,
+}
+
+impl Opt {
+    fn client_config(&self) -> Res<ClientConfig> {
+        Ok(ClientConfig {
+            player: self.player.player_kind()?,
+            media: self.media.clone(),
+            profile: Profile { nickname: self.nickname.clone(), color: None },
+            ..ClientConfig::default()
+        })
+    }
+}
+
+/// Puts the terminal back in its normal mode when dropped, even on a panic.
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn enter() -> Res<Self> {
+        enable_raw_mode()?;
+        execute!(stdout(), EnterAlternateScreen)?;
+        Ok(TerminalGuard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        disable_raw_mode().ok();
+        execute!(stdout(), LeaveAlternateScreen, crossterm::cursor::Show).ok();
+    }
+}
+
+/// Field being typed before creating or joining a session, a new session asks its name then its
+/// password.
+enum Prompt {
+    CreateName(String),
+    CreatePassword(String, String),
+    Join(SessionId, String),
+}
+
+enum Status {
+    Info(String),
+    Error(String),
+}
+
+/// Control of the session, from the last permissions sent by the server.
+struct Permissions {
+    policy: ControlPolicy,
+    controllers: Vec<PeerId>,
+}
+
+struct App {
+    client: Client,
+    sessions: ListState,
+    permissions: Option<Permissions>,
+    /// Sync of the local player, read at each redraw tick while in a session.
+    drift: Option<DriftStats>,
+    /// Last playback command of the session, with the peer who initiated it.
+    last_action: Option<(PeerId, PlayerAction)>,
+    prompt: Option<Prompt>,
+    status: Option<Status>,
+    /// Set when the application must stop, with the reason printed after the terminal is restored.
+    quit: Option<Option<String>>,
+}
+
+#[tokio::main]
+async fn main() -> Res {
+    let opt = Opt::from_args();
+    let client = create_client_with(opt.server.as_str(), opt.client_config()?).await
+        .with_context(|| format!("Couldn't connect to {}", opt.server))?;
+    let events = client.events();
+    let (keys_tx, keys) = flume::unbounded();
+    spawn_key_reader(keys_tx);
+
+    let (reason, client) = {
+        let _guard = TerminalGuard::enter()?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+        terminal.hide_cursor()?;
+        let mut app = App {
+            client,
+            sessions: ListState::default(),
+            permissions: None,
+            drift: None,
+            last_action: None,
+            prompt: None,
+            status: None,
+            quit: None,
+        };
+        let mut redraw = interval(REDRAW_TICK);
+        loop {
+            terminal.draw(|f| app.draw(f))?;
+            select! {
+                key = keys.recv_async() => app.handle_key(key?).await,
+                event = events.recv_async() => app.handle_event(event?),
+                _ = redraw.tick() => app.refresh_drift().await,
+            }
+            if let Some(reason) = app.quit.take() {
+                break (reason, app.client);
+            }
+        }
+    };
+    if let Some(reason) = reason {
+        println!("{}", reason);
+    }
+    client.close().await
+}
+
+/// Reads the terminal events on their own thread, crossterm only reads them blocking.
+fn spawn_key_reader(tx: Sender<KeyEvent>) {
+    thread::spawn(move || loop {
+        match event::poll(REDRAW_TICK).and_then(|ready| if ready { event::read().map(Some) } else { Ok(None) }) {
+            Ok(Some(Event::Key(key))) => {
+                if tx.send(key).is_err() {
+                    break;
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    });
+}
+
+impl App {
+    async fn handle_key(&mut self, key: KeyEvent) {
+        if let Some(prompt) = self.prompt.as_mut() {
+            let field = match prompt {
+                Prompt::CreateName(field) | Prompt::CreatePassword(_, field) | Prompt::Join(_, field) => field,
+            };
+            match key.code {
+                KeyCode::Char(c) => field.push(c),
+                KeyCode::Backspace => {
+                    field.pop();
+                }
+                KeyCode::Esc => self.prompt = None,
+                KeyCode::Enter => match self.prompt.take() {
+                    Some(Prompt::CreateName(name)) => self.prompt = Some(Prompt::CreatePassword(name, String::new())),
+                    Some(prompt) => {
+                        let result = self.submit(prompt).await;
+                        self.report(result);
+                    }
+                    None => {}
+                },
+                _ => {}
+            }
+            return;
+        }
+        let result = match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => {
+                self.quit = Some(None);
+                OK
+            }
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.quit = Some(None);
+                OK
+            }
+            KeyCode::Up => {
+                self.move_selection(-1);
+                OK
+            }
+            KeyCode::Down => {
+                self.move_selection(1);
+                OK
+            }
+            KeyCode::Enter => {
+                if let Some(session) = self.selected_session() {
+                    self.prompt = Some(Prompt::Join(session.id, String::new()));
+                }
+                OK
+            }
+            KeyCode::Char('c') => {
+                self.prompt = Some(Prompt::CreateName(String::new()));
+                OK
+            }
+            KeyCode::Char('l') => self.client.leave_session().await.map(|_| self.info("Session left")),
+            KeyCode::Char('s') => self.client.start_session().await.map(|_| self.info("Session started")),
+            KeyCode::Char(' ') => {
+                if self.client.player().state == PlayerState::Playing {
+                    self.client.pause().await
+                } else {
+                    self.client.play().await
+                }
+            }
+            KeyCode::Left => {
+                let position = self.client.player().position;
+                self.client.seek(position.checked_sub(SEEK_STEP).unwrap_or_default()).await
+            }
+            KeyCode::Right => {
+                let position = self.client.player().position;
+                self.client.seek(position + SEEK_STEP).await
+            }
+            _ => OK,
+        };
+        self.report(result);
+    }
+
+    async fn submit(&mut self, prompt: Prompt) -> Res {
+        match prompt {
+            // The name is confirmed before the password
+            Prompt::CreateName(_) => {}
+            Prompt::CreatePassword(name, password) => {
+                let session_id = self.client.create_session(name, password).await?;
+                self.info(format!("Session {} created", short_id(session_id)));
+            }
+            Prompt::Join(session_id, password) => {
+                self.client.join_session(session_id, password).await?;
+                self.info(format!("Joined {}", short_id(session_id)));
+            }
+        }
+        OK
+    }
+
+    fn handle_event(&mut self, event: ClientEvent) {
+        match event {
+            ClientEvent::Error(error) => self.status = Some(Status::Error(format!("Server error: {}", error))),
+            ClientEvent::ActionRefused(action, error) => {
+                self.status = Some(Status::Error(format!("You {} in the player but {}", describe_action(action), error)));
+            }
+            ClientEvent::Playback(peer_id, action) => {
+                let message = format!("{} {}", self.nickname(peer_id), describe_action(action));
+                self.info(message);
+                self.last_action = Some((peer_id, action));
+            }
+            ClientEvent::Permissions { policy, controllers, .. } => {
+                self.permissions = Some(Permissions { policy, controllers });
+            }
+            ClientEvent::SessionJoined(_) | ClientEvent::SessionLeft(_) => {
+                self.permissions = None;
+                self.drift = None;
+                self.last_action = None;
+            }
+            ClientEvent::PeerLeft(peer_id) => {
+                let message = format!("{} left the session", self.nickname(peer_id));
+                self.info(message);
+            }
+            ClientEvent::Reconnecting(attempt) => {
+                self.status = Some(Status::Error(format!("Connection lost, reconnection attempt {}", attempt)));
+            }
+            ClientEvent::Reconnected { resumed, .. } => {
+                self.info(if resumed { "Reconnected, session resumed" } else { "Reconnected as a new peer" });
+            }
+            ClientEvent::Disconnected => self.quit = Some(Some("Disconnected from the server".to_string())),
+            _ => {}
+        }
+    }
+
+    async fn refresh_drift(&mut self) {
+        if self.client.my_session().is_some() {
+            self.drift = self.client.drift_stats().await.ok();
+        }
+    }
+
+    fn report(&mut self, result: Res) {
+        if let Err(e) = result {
+            self.status = Some(Status::Error(e.to_string()));
+        }
+    }
+
+    fn info(&mut self, message: impl Into<String>) {
+        self.status = Some(Status::Info(message.into()));
+    }
+
+    fn sessions(&self) -> Vec<SessionDTO> {
+        let mut sessions = self.client.sessions();
+        sessions.sort_by_key(|s| s.id);
+        sessions
+    }
+
+    fn selected_session(&self) -> Option<SessionDTO> {
+        self.sessions.selected().and_then(|i| self.sessions().into_iter().nth(i))
+    }
+
+    fn move_selection(&mut self, step: isize) {
+        let count = self.sessions().len();
+        if count == 0 {
+            return self.sessions.select(None);
+        }
+        let current = self.sessions.selected().unwrap_or(0) as isize;
+        self.sessions.select(Some((current + step).rem_euclid(count as isize) as usize));
+    }
+
+    fn nickname(&self, peer_id: PeerId) -> String {
+        self.client.connected_peers().into_iter()
+            .find(|p| p.id == peer_id)
+            .map(|p| p.pseudo)
+            .unwrap_or_else(|| short_id(peer_id))
+    }
+
+    fn draw(&mut self, f: &mut Frame<Backend>) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(5), Constraint::Length(3), Constraint::Length(1), Constraint::Length(1)])
+            .split(f.size());
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+            .split(rows[0]);
+        self.draw_sessions(f, panes[0]);
+        self.draw_participants(f, panes[1]);
+        self.draw_playback(f, rows[1]);
+        self.draw_status(f, rows[2]);
+        f.render_widget(Paragraph::new(HELP).style(Style::default().fg(Color::DarkGray)), rows[3]);
+    }
+
+    fn draw_sessions(&mut self, f: &mut Frame<Backend>, area: Rect) {
+        let sessions = self.sessions();
+        // The list may have shrunk since the last selection
+        match self.sessions.selected() {
+            Some(i) if i >= sessions.len() => self.sessions.select(sessions.len().checked_sub(1)),
+            None if !sessions.is_empty() => self.sessions.select(Some(0)),
+            _ => {}
+        }
+        let my_session = self.client.my_session().map(|s| s.id);
+        let items: Vec<ListItem> = sessions.iter().map(|session| {
+            let marker = if Some(session.id) == my_session { "* " } else { "  " };
+            let participants = session.participants.as_ref().map_or(0, Vec::len);
+            let state = if session.started { "started" } else { "waiting" };
+            ListItem::new(format!("{}{} {} ({}), {}", marker, session.name, short_id(session.id), participants, state))
+        }).collect();
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title("Sessions"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        f.render_stateful_widget(list, area, &mut self.sessions);
+    }
+
+    fn draw_participants(&self, f: &mut Frame<Backend>, area: Rect) {
+        let session = self.client.my_session();
+        let title = match &session {
+            Some(session) => format!("Session {} {}", session.name, short_id(session.id)),
+            None => "Not in a session".to_string(),
+        };
+        let me = self.client.hub_state().map(|s| s.me.id);
+        let sync = self.client.peer_sync();
+        let player = self.client.player();
+        let rows: Vec<Row> = session.iter()
+            .flat_map(|session| session.participants.iter().flatten().map(move |peer| (session.owner, peer)))
+            .map(|(owner, peer)| {
+                let mut name = peer.pseudo.clone();
+                if Some(peer.id) == me {
+                    name.push_str(" (you)");
+                }
+                let role = match &self.permissions {
+                    _ if peer.id == owner => "owner",
+                    Some(permissions) if permissions.policy == ControlPolicy::Restricted
+                        && !permissions.controllers.contains(&peer.id) => "viewer",
+                    _ => "controller",
+                };
+                // The local player is read directly, the others from their last report
+                let playback = if Some(peer.id) == me {
+                    Some((player.position, player.state == PlayerState::Playing, self.drift.map(|stats| stats.last_drift)))
+                } else {
+                    sync.get(&peer.id).map(|report| (Duration::from_millis(report.position), report.playing, Some(report.drift)))
+                };
+                let position = match playback {
+                    Some((position, playing, _)) => format!("{} {}", if playing { "▶" } else { "⏸" }, format_position(position)),
+                    None => "-".to_string(),
+                };
+                let (drift, drift_style) = match playback {
+                    Some((_, _, Some(drift))) => (format!("{:+} ms", drift), drift_style(drift)),
+                    _ => ("-".to_string(), Style::default()),
+                };
+                Row::new(vec![Cell::from(name), Cell::from(role), Cell::from(position), Cell::from(drift).style(drift_style)])
+            })
+            .collect();
+        let widths = [Constraint::Percentage(40), Constraint::Percentage(20), Constraint::Percentage(20), Constraint::Percentage(20)];
+        let table = Table::new(rows)
+            .header(Row::new(vec!["Participant", "Role", "Position", "Drift"]).style(Style::default().add_modifier(Modifier::BOLD)))
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .widths(&widths);
+        f.render_widget(table, area);
+    }
+
+    fn draw_playback(&self, f: &mut Frame<Backend>, area: Rect) {
+        let player = self.client.player();
+        let symbol = match player.state {
+            PlayerState::Playing => "▶",
+            PlayerState::Paused => "⏸",
+            PlayerState::Stopping => "■",
+        };
+        let mut line = vec![Span::styled(format!("{} {}", symbol, format_position(player.position)),
+                                         Style::default().fg(Color::Cyan))];
+        if let Some(stats) = &self.drift {
+            line.push(Span::raw(format!("  rate {:.3}, average drift {:.1} ms", stats.rate, stats.average_drift)));
+        }
+        if let Some((peer_id, action)) = self.last_action {
+            line.push(Span::styled(format!("  last: {} {}", self.nickname(peer_id), describe_action(action)),
+                                   Style::default().fg(Color::DarkGray)));
+        }
+        let playback = Paragraph::new(Spans::from(line))
+            .block(Block::default().borders(Borders::ALL).title("Playback"));
+        f.render_widget(playback, area);
+    }
+
+    fn draw_status(&self, f: &mut Frame<Backend>, area: Rect) {
+        let line = match (&self.prompt, &self.status) {
+            (Some(prompt), _) => {
+                let (action, field) = match prompt {
+                    Prompt::CreateName(name) => ("New session name", name.clone()),
+                    Prompt::CreatePassword(_, password) => ("New session password", "*".repeat(password.chars().count())),
+                    Prompt::Join(_, password) => ("Session password", "*".repeat(password.chars().count())),
+                };
+                Spans::from(vec![
+                    Span::styled(format!("{}: ", action), Style::default().fg(Color::Yellow)),
+                    Span::raw(field),
+                    Span::styled("  enter to confirm, esc to cancel", Style::default().fg(Color::DarkGray)),
+                ])
+            }
+            (None, Some(Status::Error(message))) => Spans::from(Span::styled(message.clone(), Style::default().fg(Color::Red))),
+            (None, Some(Status::Info(message))) => Spans::from(Span::styled(message.clone(), Style::default().fg(Color::Green))),
+            (None, None) => Spans::default(),
+        };
+        f.render_widget(Paragraph::new(line), area);
+    }
+}
+
+fn drift_style(drift: i64) -> Style {
+    match drift.abs() {
+        drift if drift > 200 => Style::default().fg(Color::Red),
+        drift if drift > 40 => Style::default().fg(Color::Yellow),
+        _ => Style::default(),
+    }
+}
+
+fn short_id(id: uuid::Uuid) -> String {
+    id.to_simple().to_string()[..8].to_string()
+}
@@ -0,0 +1,186 @@
+use std::ffi::CString;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use log::*;
+use vlc::{Instance, Media, MediaPlayer, State};
+
+use crate::client::player::{PlayerBackend, PlayerEvent, PlayerState};
+use crate::server::{Res, OK};
+
+/// Gap between the position read and the one expected from the previous poll above which the
+/// user is considered to have seeked.
+const SEEK_THRESHOLD: Duration = Duration::from_millis(1000);
+
+/// Backend driving libvlc, it must live on the thread that created it.
+pub struct VlcPlayer {
+    headless: bool,
+    // Declared before the instance to be released first
+    player: MediaPlayer,
+    instance: Instance,
+    stopping: bool,
+    ended: bool,
+    rate: f64,
+    /// Position read at the last poll, to detect the seeks done in the player.
+    last_poll: Option<(Duration, Instant)>,
+    /// Target of the seek commanded, reported once vlc reached it as it seeks asynchronously.
+    seeking: Option<(Duration, Instant)>,
+}
+
+impl VlcPlayer {
+    pub fn new(headless: bool) -> Res<Self> {
+        let instance = Instance::new().context("Couldn't create the vlc instance")?;
+        let player = MediaPlayer::new(&instance).context("Couldn't create the vlc media player")?;
+        Ok(VlcPlayer {
+            headless,
+            player,
+            instance,
+            stopping: false,
+            ended: false,
+            rate: 1.0,
+            last_poll: None,
+            seeking: None,
+        })
+    }
+
+    /// Returns true if the position moved away from the one expected since the last poll.
+    fn jumped(&self, position: Duration, now: Instant) -> bool {
+        let (last_position, last_poll) = match self.last_poll {
+            Some(last) => last,
+            None => return false,
+        };
+        let expected = match self.player.state() {
+            State::Playing => last_position + now.duration_since(last_poll).mul_f64(self.rate),
+            _ => last_position,
+        };
+        position.abs_diff(expected) > SEEK_THRESHOLD
+    }
+}
+
+impl PlayerBackend for VlcPlayer {
+    fn load(&mut self, path: &str) -> Res {
+        let media = Media::new_path(&self.instance, path)
+            .with_context(|| format!("Couldn't open the media {}", path))?;
+        if self.headless {
+            for option in &[":no-video", ":no-audio"] {
+                let option = CString::new(*option)?;
+                unsafe { vlc::sys::libvlc_media_add_option(media.raw(), option.as_ptr()) };
+            }
+        }
+        self.player.set_media(&media);
+        // Reported as a seek to the start, like the other backends
+        self.seeking = Some((Duration::from_millis(0), Instant::now()));
+        info!("Media {} loaded in vlc", path);
+        OK
+    }
+
+    fn play(&mut self) -> Res {
+        self.player.play().map_err(|_| anyhow!("vlc couldn't play the media"))
+    }
+
+    fn pause(&mut self) -> Res {
+        self.player.set_pause(true);
+        OK
+    }
+
+    fn stop(&mut self) -> Res {
+        self.player.stop();
+        self.stopping = true;
+        OK
+    }
+
+    fn seek(&mut self, position: Duration) -> Res {
+        self.player.set_time(position.as_millis() as i64);
+        self.seeking = Some((position, Instant::now()));
+        OK
+    }
+
+    fn set_rate(&mut self, rate: f64) -> Res {
+        self.player.set_rate(rate as f32).map_err(|_| anyhow!("vlc refused the rate {}", rate))?;
+        self.rate = rate;
+        OK
+    }
+
+    fn position(&self) -> Duration {
+        self.player.get_time()
+            .map(|t| Duration::from_millis(t.max(0) as u64))
+            .unwrap_or_default()
+    }
+
+    fn state(&self) -> PlayerState {
+        if self.stopping {
+            return PlayerState::Stopping;
+        }
+        match self.player.state() {
+            State::Playing | State::Opening | State::Buffering => PlayerState::Playing,
+            State::Error => PlayerState::Stopping,
+            State::NothingSpecial | State::Paused | State::Stopped | State::Ended => PlayerState::Paused,
+        }
+    }
+
+    fn poll(&mut self) -> Vec<PlayerEvent> {
+        let mut events = Vec::new();
+        let position = self.position();
+        let now = Instant::now();
+        let seeked = match self.seeking {
+            // A seek which doesn't reach its target is still reported
+            Some((target, at)) => {
+                position.abs_diff(target) <= SEEK_THRESHOLD || at.elapsed() > SEEK_THRESHOLD
+            }
+            None => self.jumped(position, now),
+        };
+        if seeked {
+            self.seeking = None;
+            events.push(PlayerEvent::Seeked(position));
+        }
+        self.last_poll = Some((position, now));
+
+        let ended = self.player.state() == State::Ended;
+        if ended && !self.ended {
+            events.push(PlayerEvent::EndReached);
+        }
+        self.ended = ended;
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    /// Polls the player until the condition holds, returns the events polled meanwhile.
+    fn poll_until(player: &mut VlcPlayer, condition: impl Fn(&VlcPlayer) -> bool) -> Vec<PlayerEvent> {
+        let start = Instant::now();
+        let mut events = Vec::new();
+        while !condition(player) {
+            assert!(start.elapsed() < Duration::from_secs(5), "vlc didn't reach the expected state");
+            events.extend(player.poll());
+            sleep(Duration::from_millis(20));
+        }
+        events.extend(player.poll());
+        events
+    }
+
+    #[test]
+    #[ignore = "needs libvlc installed"]
+    fn plays_seeks_and_pauses_the_test_video() {
+        let mut player = VlcPlayer::new(true).unwrap();
+        player.load(concat!(env!("CARGO_MANIFEST_DIR"), "/test_vid.mp4")).unwrap();
+        player.play().unwrap();
+        poll_until(&mut player, |p| p.state() == PlayerState::Playing && p.position() > Duration::from_millis(0));
+
+        player.seek(Duration::from_secs(3)).unwrap();
+        let events = poll_until(&mut player, |p| p.position() >= Duration::from_secs(3));
+        assert!(events.iter().any(|e| matches!(e, PlayerEvent::Seeked(_))));
+
+        player.pause().unwrap();
+        poll_until(&mut player, |p| p.player.state() == State::Paused);
+        assert_eq!(player.state(), PlayerState::Paused);
+        let position = player.position();
+        assert!(position >= Duration::from_secs(3), "position {:?} before the seek", position);
+        sleep(Duration::from_millis(200));
+        assert_eq!(player.position(), position);
+    }
+}
@@ -6,17 +6,13 @@ pub mod client;
 
 pub trait Ignore {
     #[inline(always)]
-    fn ignore(&self) -> () {
-        ()
-    }
+    fn ignore(&self) {}
 }
 
 impl<T> Ignore for T {}
 
 #[inline(always)]
-pub fn ignore() -> () {
-    ()
-}
+pub fn ignore() {}
 
 
 pub trait Builder where Self: Sized {
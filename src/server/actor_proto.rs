@@ -1,11 +1,18 @@
-use crate::server::{PeerId};
-use crate::server::net_proto::Input;
+use flume::Sender;
+use tokio::time::Duration;
+use uuid::Uuid;
+
+use crate::server::PeerId;
+use crate::server::net_proto::{InputAction, RequestId, Resume};
 use crate::server::peer::Peer;
 
 #[derive(Clone, Debug)]
 pub enum LogInAction {
     Connected(Peer),
-    Disconnect(PeerId)
+    /// Tells whether the resume token is valid, the connection then takes over the peer.
+    Resume(Resume, Sender<bool>),
+    /// The given connection of the peer is closed.
+    Disconnect(PeerId, Uuid)
 }
 
 
@@ -13,12 +20,20 @@ pub enum LogInAction {
 #[non_exhaustive]
 pub enum HubMessage {
     LogInAction(LogInAction),
-    NetInput(Input),
+    /// Input received on the given connection of the peer, dropped if it is not its current one.
+    NetInput(PeerId, Uuid, Option<RequestId>, InputAction),
+    /// Stops the hub, its sessions are stopped with it.
+    Shutdown,
 }
 
 #[derive(Clone, Debug)]
 pub enum SessionMessage {
-    Play,
-    Pause,
-    Stop
+    Play(PeerId),
+    Pause(PeerId),
+    Stop(PeerId),
+    Seek(PeerId, Duration),
+    /// Media shared with the session, played from its start.
+    Share(String),
+    AddPeer(Peer),
+    RemovePeer(PeerId),
 }
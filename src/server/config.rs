@@ -0,0 +1,150 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{ensure, Context};
+use log::LevelFilter;
+use serde::Deserialize;
+use tokio::time::Duration;
+
+use crate::server::Res;
+
+/// Configuration of the daemon, every field has a default so a config file only needs the ones it
+/// changes.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses the server listens on.
+    pub bind: Vec<SocketAddr>,
+    /// Time without any input, pings included, after which a peer is disconnected.
+    pub peer_timeout_ms: u64,
+    /// Interval between two timestamps of a running session.
+    pub session_tick_ms: u64,
+    /// Time during which a disconnected peer can resume its connection.
+    pub resume_grace_ms: u64,
+    pub limits: Limits,
+    pub log: LogConfig,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Simultaneous connections.
+    pub max_peers: usize,
+    pub max_sessions: usize,
+    pub max_participants: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// One of off, error, warn, info, debug, trace.
+    pub level: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: vec![SocketAddr::from(([127, 0, 0, 1], 5135))],
+            peer_timeout_ms: 20000,
+            session_tick_ms: 40,
+            resume_grace_ms: 30000,
+            limits: Limits::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_peers: 1000,
+            max_sessions: 200,
+            max_participants: 50,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig { level: "info".to_string() }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> Res<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read the config file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn validate(&self) -> Res {
+        ensure!(!self.bind.is_empty(), "At least one bind address is needed");
+        ensure!(self.peer_timeout_ms >= 1000, "The peer timeout must be at least 1000ms, clients ping every 100ms");
+        ensure!((10..=1000).contains(&self.session_tick_ms), "The session tick must be between 10 and 1000ms");
+        ensure!(self.limits.max_peers > 0, "The maximum number of peers must be positive");
+        ensure!(self.limits.max_sessions > 0, "The maximum number of sessions must be positive");
+        ensure!(self.limits.max_participants > 0, "The maximum number of participants must be positive");
+        self.log_level()?;
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Res<LevelFilter> {
+        LevelFilter::from_str(&self.log.level)
+            .with_context(|| format!("Unknown log level {:?}, expected off, error, warn, info, debug or trace", self.log.level))
+    }
+
+    pub fn peer_timeout(&self) -> Duration {
+        Duration::from_millis(self.peer_timeout_ms)
+    }
+
+    pub fn session_tick(&self) -> Duration {
+        Duration::from_millis(self.session_tick_ms)
+    }
+
+    pub fn resume_grace(&self) -> Duration {
+        Duration::from_millis(self.resume_grace_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_is_valid() {
+        ServerConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn missing_fields_take_their_default() {
+        let config: ServerConfig = toml::from_str("peer_timeout_ms = 5000\n[limits]\nmax_peers = 3").unwrap();
+        assert_eq!(config.peer_timeout(), Duration::from_secs(5));
+        assert_eq!(config.limits.max_peers, 3);
+        assert_eq!(config.limits.max_sessions, Limits::default().max_sessions);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(toml::from_str::<ServerConfig>("peer_timeout = 5000").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let invalid = [
+            ServerConfig { bind: vec![], ..ServerConfig::default() },
+            ServerConfig { peer_timeout_ms: 999, ..ServerConfig::default() },
+            ServerConfig { session_tick_ms: 5, ..ServerConfig::default() },
+            ServerConfig { session_tick_ms: 2000, ..ServerConfig::default() },
+            ServerConfig { limits: Limits { max_peers: 0, ..Limits::default() }, ..ServerConfig::default() },
+            ServerConfig { limits: Limits { max_sessions: 0, ..Limits::default() }, ..ServerConfig::default() },
+            ServerConfig { limits: Limits { max_participants: 0, ..Limits::default() }, ..ServerConfig::default() },
+            ServerConfig { log: LogConfig { level: "verbose".to_string() }, ..ServerConfig::default() },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{:?} accepted", config);
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use anyhow::{anyhow, ensure, Context};
+use flume::{bounded, unbounded, Receiver, Sender};
+use log::*;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::runtime::Handle;
+use tokio::select;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+use uuid::Uuid;
+
+use crate::ignore;
+use crate::server::{HubTransmitter, PeerEventReader, PeerId, Res, OK};
+use crate::server::actor_proto::{HubMessage, LogInAction};
+use crate::server::config::ServerConfig;
+use crate::server::hub::Hub;
+use crate::server::net_proto::{Hello, HelloReply, Input, Output, Profile, Refusal, Resume, PROTOCOL_VERSION};
+use crate::server::peer::{Peer, PeerProxy};
+use crate::server::util::{into_raw_split, read_frame, typed_reader, typed_writer, write_frame, NetReader, NetWriter, RawReader};
+
+/// Configures and starts a [`Server`].
+#[derive(Clone, Debug, Default)]
+pub struct ServerBuilder {
+    config: ServerConfig,
+    bind: Vec<SocketAddr>,
+}
+
+impl ServerBuilder {
+    /// Replaces the configuration, the addresses given to `bind` are kept.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Listens on the address instead of the ones of the configuration, can be called several
+    /// times. The port 0 takes any free port, see [`Server::local_addrs`].
+    pub fn bind(mut self, addr: impl Into<SocketAddr>) -> Self {
+        self.bind.push(addr.into());
+        self
+    }
+
+    /// Binds every address and starts the hub, it must be called inside a tokio runtime.
+    pub async fn start(self) -> Res<Server> {
+        let mut config = self.config;
+        if !self.bind.is_empty() {
+            config.bind = self.bind;
+        }
+        config.validate().context("Invalid configuration")?;
+
+        let mut listeners = Vec::new();
+        let mut local_addrs = Vec::new();
+        for addr in &config.bind {
+            let listener = TcpListener::bind(addr).await.with_context(|| format!("Couldn't listen on {}", addr))?;
+            let local_addr = listener.local_addr()?;
+            info!("Listening on {}", local_addr);
+            listeners.push(listener);
+            local_addrs.push(local_addr);
+        }
+
+        let (hub_tx, rx) = unbounded();
+        let handle = Handle::current();
+        let hub_config = config.clone();
+        let hub = thread::spawn(move || {
+            Hub::new(rx, handle, hub_config).run();
+        });
+
+        let (shutdown, shutdown_rx) = bounded(1);
+        let acceptor = Acceptor {
+            hub_tx: hub_tx.clone(),
+            connections: Connections { count: Arc::new(AtomicUsize::new(0)), max: config.limits.max_peers },
+            peer_timeout: config.peer_timeout(),
+            shutdown: shutdown_rx,
+        };
+        let accepts = listeners.into_iter()
+            .map(|listener| tokio::spawn(acceptor.clone().accept(listener)))
+            .collect();
+        Ok(Server { local_addrs, hub_tx, shutdown: Some(shutdown), accepts, hub: Some(hub) })
+    }
+}
+
+/// Running server, it is shut down when dropped.
+pub struct Server {
+    local_addrs: Vec<SocketAddr>,
+    hub_tx: HubTransmitter,
+    /// Dropped to stop the accepting tasks and the connections.
+    shutdown: Option<Sender<()>>,
+    accepts: Vec<JoinHandle<()>>,
+    hub: Option<thread::JoinHandle<()>>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// Addresses actually bound, in the order of the configuration.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// First bound address.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// Stops accepting connections, closes the open ones and waits for the hub to stop its sessions.
+    pub async fn shutdown(mut self) -> Res {
+        self.stop();
+        for accept in self.accepts.drain(..) {
+            accept.await?;
+        }
+        if let Some(hub) = self.hub.take() {
+            tokio::task::spawn_blocking(move || hub.join()).await?
+                .map_err(|_| anyhow!("The hub panicked"))?;
+        }
+        info!("Server stopped");
+        OK
+    }
+
+    fn stop(&mut self) {
+        if self.shutdown.take().is_some() {
+            info!("Shutting down the server");
+            if self.hub_tx.send(HubMessage::Shutdown).is_err() {
+                debug!("The hub is already stopped");
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Counts the open connections to refuse the ones above the limit.
+#[derive(Clone)]
+struct Connections {
+    count: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// Place of an open connection, released when dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Connections {
+    fn take(&self) -> Option<ConnectionSlot> {
+        self.count.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < self.max).then(|| count + 1))
+            .ok()
+            .map(|_| ConnectionSlot(self.count.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accepts the connections of a listener and wires each new peer to the hub.
+#[derive(Clone)]
+struct Acceptor {
+    hub_tx: HubTransmitter,
+    connections: Connections,
+    peer_timeout: Duration,
+    /// Disconnected when the server shuts down.
+    shutdown: Receiver<()>,
+}
+
+impl Acceptor {
+    async fn accept(self, listener: TcpListener) {
+        loop {
+            let accepted = select! {
+                accepted = listener.accept() => accepted,
+                _ = self.shutdown.recv_async() => break,
+            };
+            let (socket, addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Couldn't accept a connection: {}", e);
+                    continue;
+                }
+            };
+            info!("Connection accepted from : {}", addr);
+            let acceptor = self.clone();
+            let slot = self.connections.take();
+            tokio::spawn(async move {
+                match acceptor.handle_first_connection(socket, slot).await {
+                    Ok(_) => ignore(),
+                    Err(e) => error!("Error handling first connection: {}, from {}", e, addr)
+                }
+            });
+        }
+    }
+
+    async fn handle_first_connection(self, stream: TcpStream, slot: Option<ConnectionSlot>) -> Res<Peer> {
+        let (mut rs, mut ws) = into_raw_split(stream);
+
+        // The slot is taken, a client which doesn't present itself must not keep it
+        let hello = timeout(self.peer_timeout, read_hello(&mut rs)).await
+            .unwrap_or_else(|_| Err(anyhow!("No hello received after {:?}", self.peer_timeout)));
+        let refusal = match (&hello, &slot) {
+            (Err(_), _) => Some(Refusal::InvalidProtocol),
+            (Ok(_), None) => Some(Refusal::LimitReached),
+            (Ok(_), Some(_)) => None,
+        };
+        let features = hello.as_ref().map(Hello::accepted_features).unwrap_or_default();
+        write_frame(&mut ws, HelloReply { version: PROTOCOL_VERSION, features, refusal }).await?;
+        let hello = hello?;
+        let slot = slot.context("Too many connections")?;
+        self.connect_peer(typed_reader(rs), typed_writer(ws), hello.profile, hello.resume, slot).await
+    }
+
+    /// Id of the peer to resume if its token is valid, a new one otherwise.
+    async fn resumed_peer_id(&self, resume: Option<Resume>) -> Res<PeerId> {
+        if let Some(resume) = resume {
+            let (tx, rx) = bounded(1);
+            self.hub_tx.send_async(HubMessage::LogInAction(LogInAction::Resume(resume, tx))).await?;
+            if rx.recv_async().await? {
+                return Ok(resume.peer);
+            }
+            info!("Invalid resume token for {}, connected as a new peer", resume.peer);
+        }
+        Ok(PeerId::new_v4())
+    }
+
+    async fn connect_peer(self, rs: NetReader<Input, OwnedReadHalf>, ws: NetWriter<Output, OwnedWriteHalf>,
+                          profile: Profile, resume: Option<Resume>, slot: ConnectionSlot) -> Res<Peer> {
+        let (peer_t, peer_r) = unbounded();
+        let (close, closed) = bounded(1);
+
+        let peer_id = self.resumed_peer_id(resume).await?;
+        let connection = Uuid::new_v4();
+        // The nickname is validated by the hub
+        let peer = Peer {
+            id: peer_id,
+            pseudo: profile.nickname,
+            color: profile.color,
+            connection,
+            proxy_tx: peer_t,
+            close,
+        };
+
+        let peer_proxy_handle = tokio::spawn(async move {
+            let error = PeerProxy::new(peer_r, ws).run().await.unwrap_err();
+            info!("Peer: {} reader stopped, {}", peer_id, error);
+        });
+
+        self.hub_tx.send_async(HubMessage::LogInAction(LogInAction::Connected(peer.clone()))).await?;
+        info!("User logged, id created: {}", peer_id);
+        let mut event_loop = PeerEventReader {
+            net_reader: rs,
+            hub_tx: self.hub_tx.clone(),
+            peer_tx: peer.proxy_tx.clone(),
+            connection,
+        };
+        tokio::spawn(async move {
+            trace!("Starting user: {} event loop", peer_id);
+            let e = select! {
+                res = event_loop.run(self.peer_timeout, peer_id) => res.unwrap_err(),
+                _ = self.shutdown.recv_async() => anyhow!("The server shuts down"),
+                Ok(()) = closed.recv_async() => anyhow!("A resumed connection took over the peer"),
+            };
+            info!("Connection to {} closed, error: {}", peer_id, e);
+            let disconnect = HubMessage::LogInAction(LogInAction::Disconnect(peer_id, connection));
+            if event_loop.hub_tx.send_async(disconnect).await.is_err() {
+                debug!("The hub is stopped, disconnection of {} not sent", peer_id);
+            }
+            peer_proxy_handle.abort();
+            drop(slot);
+        });
+        trace!("user : {}, event loop started", peer_id);
+        Ok(peer)
+    }
+}
+
+/// Reads the version frame, then the hello if the client speaks the same protocol.
+async fn read_hello(rs: &mut RawReader<OwnedReadHalf>) -> Res<Hello> {
+    let version: u32 = read_frame(rs).await
+        .context("Couldn't read the protocol version")?
+        .context("The connection closed before the hello")?;
+    ensure!(version == PROTOCOL_VERSION, "Client speaks the protocol {}, expected {}", version, PROTOCOL_VERSION);
+    read_frame(rs).await
+        .context("Couldn't read the hello")?
+        .context("The connection closed before the hello")
+}
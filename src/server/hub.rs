@@ -1,18 +1,24 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 
-use flume::Receiver;
+use flume::{unbounded, Receiver, RecvError, Selector, Sender};
+use flume::select::SelectError;
 use log::*;
-use tap::prelude::Pipe;
 use tokio::time::Duration;
 
-use crate::server::{PeerId, REFRESH_TICK, SessionId, Res, OK};
+use crate::server::{PeerId, SessionId, Res, OK};
+use crate::server::config::ServerConfig;
 use crate::server::actor_proto::{HubMessage, LogInAction};
-use crate::server::net_proto::{HubAction, Input, InputAction, Output, PlayerAction, HubState, SessionDTO, PeerDTO, OutputError};
+use crate::server::net_proto::{HubAction, InputAction, Output, PlayerAction, HubState, HubDelta, SessionDTO, PeerDTO, OutputError, Profile, Ack, RequestId, Resume, ResumeToken, SyncReport};
 use crate::server::peer::Peer;
-use crate::server::session::Session;
+use crate::server::session::{hash_password, verify_password, Session};
 use tokio::runtime::Handle;
-use crate::ignore;
-use anyhow::Context;
+use crate::{ignore, Ignore};
+use anyhow::{bail, ensure, Context};
+
+const EXPIRY_CHECK: Duration = Duration::from_secs(1);
+/// Password checks running at once, each argon2 run takes about 19 MiB on a blocking thread.
+const MAX_PASSWORD_CHECKS: usize = 8;
 
 #[derive(Debug, Clone)]
 pub enum PeerStatus {
@@ -20,99 +26,375 @@ pub enum PeerStatus {
     Idle,
 }
 
+/// Outcome of the password work run off the hub thread, it completes the request of the peer.
+struct PasswordCheck {
+    from: PeerId,
+    request: Option<RequestId>,
+    outcome: PasswordOutcome,
+}
+
+enum PasswordOutcome {
+    /// Name of the new session and the hash of its password.
+    Hashed(String, Res<Option<String>>),
+    /// Whether the password matches the one of the session to join.
+    Verified(SessionId, bool),
+}
+
+enum Received {
+    Message(Result<HubMessage, RecvError>),
+    Check(Result<PasswordCheck, RecvError>),
+}
+
 pub struct Hub {
     sessions: HashMap<SessionId, Session>,
     connected: HashMap<PeerId, (Peer, PeerStatus)>,
     r_messages: Receiver<HubMessage>,
+    checks_tx: Sender<PasswordCheck>,
+    checks: Receiver<PasswordCheck>,
+    /// Peers with a password check running, they can't start another one.
+    checking: HashSet<PeerId>,
     handle_runtime: Handle,
+    /// Sequence number of the last hub delta sent.
+    seq: u64,
+    resume_tokens: HashMap<PeerId, ResumeToken>,
+    /// Peers which lost their connection, with the time of the loss.
+    detached: HashMap<PeerId, Instant>,
+    config: ServerConfig,
 }
 
 impl Hub {
-    pub fn new(rx: Receiver<HubMessage>, handle_runtime: Handle) -> Self {
+    pub fn new(rx: Receiver<HubMessage>, handle_runtime: Handle, config: ServerConfig) -> Self {
+        let (checks_tx, checks) = unbounded();
         Hub {
             sessions: HashMap::new(),
             connected: HashMap::new(),
             r_messages: rx,
+            checks_tx,
+            checks,
+            checking: HashSet::new(),
             handle_runtime,
+            seq: 0,
+            resume_tokens: HashMap::new(),
+            detached: HashMap::new(),
+            config,
         }
     }
 
     pub fn run(&mut self) {
         loop {
-            match self.r_messages.recv() {
-                Ok(message) => {
+            let received = Selector::new()
+                .recv(&self.r_messages, Received::Message)
+                .recv(&self.checks, Received::Check)
+                .wait_timeout(EXPIRY_CHECK);
+            match received {
+                Ok(Received::Message(Ok(HubMessage::Shutdown))) => {
+                    info!("Hub stopped with {} sessions and {} peers", self.sessions.len(), self.connected.len());
+                    break;
+                }
+                Ok(Received::Message(Ok(message))) => {
                     debug!("Hub received {:?}", message);
                     match self.handle(message.clone()) {
                         Ok(_) => ignore(),
                         Err(e) => error!("Error handling message {:?}, error: {}", message, e)
                     }
                 }
-                Err(e) => {
-                    error!("Error occurred in hub: {:?}", e);
+                Ok(Received::Message(Err(RecvError::Disconnected))) => {
+                    error!("Every hub transmitter is dropped, the hub stops");
+                    break;
+                }
+                Ok(Received::Check(Ok(check))) => {
+                    if let Err(e) = self.password_checked(check) {
+                        error!("Error completing a password check, error: {}", e);
+                    }
                 }
+                // The hub keeps a sender of its checks
+                Ok(Received::Check(Err(RecvError::Disconnected))) => ignore(),
+                Err(SelectError::Timeout) => ignore(),
             }
+            self.expire_detached();
+            self.reap_sessions();
         }
     }
 
     pub fn start_session(&mut self, user_id: PeerId) -> Res {
         let (_, status) = self.connected.get(&user_id).context("The user is not connected and attempts to start a session")?;
-        if let PeerStatus::InSession(session_id) = status {
-            let session = self.sessions.get_mut(session_id).unwrap();
-            session.start(Duration::from_millis(REFRESH_TICK), &self.handle_runtime);
+        if let PeerStatus::InSession(session_id) = *status {
+            let session = self.sessions.get_mut(&session_id).context("The session of the user doesn't exist")?;
+            if !session.can_control(user_id) {
+                bail!(OutputError::Unauthorized);
+            }
+            // A stopped session can be started again before being reaped
+            session.reap();
+            if session.is_started() {
+                bail!(OutputError::InvalidRequest);
+            }
+            session.start(self.config.session_tick(), &self.handle_runtime)?;
+            self.session_updated(session_id);
             OK
         } else {
-            panic!("The user is not in a session");
+            Err(anyhow::anyhow!("The user is not in a session"))
         }
     }
 
-    pub fn create_session(&mut self, user_id: PeerId, name: String, password: String) -> SessionId {
+    /// Shares the media with the session of the peer, it is sent to the participants joining it.
+    pub fn share_media(&mut self, from: PeerId, media: String) -> Res {
+        let session = match self.get_mut_session(from) {
+            Some(session) => session,
+            None => bail!(OutputError::InvalidRequest),
+        };
+        if !session.can_control(from) {
+            bail!(OutputError::Unauthorized);
+        }
+        info!("Session {}, {} shared the media {}", session.id(), from, media);
+        session.set_media(media);
+        OK
+    }
+
+    /// Applies a modification of the permissions of the session of `from`, reserved to its owner,
+    /// and sends the new permissions to the participants.
+    pub fn change_permissions(&mut self, from: PeerId, change: impl FnOnce(&mut Session)) -> Res {
+        let session = self.get_mut_session(from).context("The peer didn't join any session")?;
+        if session.owner() != from {
+            bail!(OutputError::NotOwner);
+        }
+        change(session);
+        let permissions = session.permissions();
+        let session_id = session.id();
+        for participant in session.participants() {
+            if let Err(e) = participant.send(permissions.clone()) {
+                warn!("Couldn't send the permissions to {}, error: {}", participant.id, e);
+            }
+        }
+        self.session_updated(session_id);
+        OK
+    }
+
+    /// Returns the validated name if the peer can create the session.
+    fn check_new_session(&self, user_id: PeerId, name: &str) -> Res<String> {
+        let name = match validate_session_name(name) {
+            Some(name) => name,
+            None => bail!(OutputError::InvalidSessionName),
+        };
+        if self.sessions.len() >= self.config.limits.max_sessions {
+            warn!("Session limit of {} reached, {} can't create one", self.config.limits.max_sessions, user_id);
+            bail!(OutputError::LimitReached);
+        }
+        Ok(name)
+    }
+
+    /// Creates the session, the password is already hashed by [`hash_password`].
+    pub fn create_session(&mut self, user_id: PeerId, name: &str, password: Option<String>) -> Res<SessionId> {
+        let name = self.check_new_session(user_id, name)?;
         let new_session = Session::new(user_id, name, password);
         let session_id = new_session.id();
+        self.broadcast_delta(HubDelta::SessionCreated(SessionDTO::from(&new_session)));
         self.sessions.insert(session_id, new_session);
-        session_id
+        Ok(session_id)
     }
 
-    pub fn join_session(&mut self, peer_id: PeerId, session_to_join: SessionId, password: &str) -> Res {
-        let (peer, status) = self.connected.get(&peer_id).unwrap();
+    /// Joins the session, its password is already verified by [`verify_password`].
+    pub fn join_session(&mut self, peer_id: PeerId, session_to_join: SessionId) -> Res {
+        let session = match self.sessions.get(&session_to_join) {
+            Some(session) => session,
+            None => bail!(OutputError::UnknownSession),
+        };
+        if !session.contains_peer(peer_id) && session.participants().count() >= self.config.limits.max_participants {
+            bail!(OutputError::LimitReached);
+        }
+        self.enter_session(peer_id, session_to_join)
+    }
 
-       let option_peer = match status {
-            PeerStatus::InSession(session_id) => {
-                let session = self.sessions.get_mut(session_id).expect("The session were the user is, doesn't exist.");
-                let peer = session.rm_peer(peer_id);
-                if session.is_empty() {
-                    self.sessions.remove(session_id);
-                }
-                Some(peer)
-            }
+    /// Moves the peer from its current session to the given one, without any check.
+    fn enter_session(&mut self, peer_id: PeerId, session_to_join: SessionId) -> Res {
+        let (peer, status) = self.connected.get(&peer_id).context("The user is not connected and attempts to join a session")?;
+        let peer = peer.clone();
+        let current_session = match status {
+            PeerStatus::InSession(session_id) if *session_id == session_to_join => return peer.send(Output::Joined(session_to_join)),
+            PeerStatus::InSession(session_id) => Some(*session_id),
             PeerStatus::Idle => None
         };
 
-        let session = self.sessions.get_mut(&session_to_join).expect("The destination session doesn't exist");
-        if session.password() == password {
-            session.add_peer(option_peer.unwrap_or(peer.clone()));
-        } else {
-            todo!("PASSWORD CHECK WRONG STATE CORRUPTED")
+        if let Some(session_id) = current_session {
+            self.remove_from_session(peer_id, session_id);
         }
+        self.sessions.get_mut(&session_to_join).unwrap().add_peer(peer.clone());
         self.connected.entry(peer_id)
             .and_modify(|(_, status)| *status = PeerStatus::InSession(session_to_join));
+        self.session_updated(session_to_join);
 
-        OK
+        peer.send(Output::Joined(session_to_join))
     }
 
-    pub fn connect(&mut self, peer: Peer) -> &Peer {
+    pub fn leave_session(&mut self, peer_id: PeerId) -> Res {
+        let (peer, status) = self.connected.get_mut(&peer_id).context("The user is not connected and attempts to leave a session")?;
+        if let PeerStatus::InSession(session_id) = std::mem::replace(status, PeerStatus::Idle) {
+            peer.send(Output::PeerLeft(peer_id))?;
+            self.remove_from_session(peer_id, session_id);
+            info!("User: {}, left {}", peer_id, session_id);
+            OK
+        } else {
+            Err(anyhow::anyhow!("The user is not in a session"))
+        }
+    }
+
+    /// Removes the peer from the session and tells it to the remaining participants, the session is
+    /// stopped and removed when nobody remains.
+    fn remove_from_session(&mut self, peer_id: PeerId, session_id: SessionId) {
+        let session = match self.sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => return warn!("The session {} of the user {} doesn't exist", session_id, peer_id),
+        };
+        session.rm_peer(peer_id);
+        let new_owner = session.elect_owner();
+        if let Some(owner) = new_owner {
+            info!("Session {}, ownership transferred to {}", session_id, owner);
+        }
+        for participant in session.participants() {
+            let sent = participant.send(Output::PeerLeft(peer_id))
+                .and_then(|_| match new_owner {
+                    Some(_) => participant.send(session.permissions()),
+                    None => OK,
+                });
+            if let Err(e) = sent {
+                warn!("Couldn't tell {} that {} left, error: {}", participant.id, peer_id, e);
+            }
+        }
+        if session.is_empty() {
+            session.stop();
+            self.sessions.remove(&session_id);
+            info!("Session {} is empty, removed", session_id);
+            self.broadcast_delta(HubDelta::SessionRemoved(session_id));
+        } else {
+            self.session_updated(session_id);
+        }
+    }
+
+    pub fn connect(&mut self, mut peer: Peer) -> &Peer {
         let id = peer.id;
+        let nickname = if peer.pseudo.is_empty() {
+            guest_nickname(id)
+        } else {
+            match validate_nickname(&peer.pseudo) {
+                Some(nickname) => nickname,
+                None => {
+                    warn!("Invalid nickname {:?} from {}", peer.pseudo, id);
+                    guest_nickname(id)
+                }
+            }
+        };
+        peer.pseudo = self.unique_nickname(id, &nickname);
+        self.broadcast_delta(HubDelta::PeerConnected(PeerDTO::from(&peer)));
         self.connected.insert(id, (peer, PeerStatus::Idle));
         &self.connected.get(&id).unwrap().0
     }
 
+    /// Appends a number to the nickname while another peer uses it.
+    fn unique_nickname(&self, peer_id: PeerId, nickname: &str) -> String {
+        let taken = |candidate: &str| self.connected.values()
+            .any(|(p, _)| p.id != peer_id && p.pseudo.to_lowercase() == candidate.to_lowercase());
+        let mut candidate = nickname.to_string();
+        let mut suffix = 2;
+        while taken(&candidate) {
+            candidate = format!("{}#{}", nickname, suffix);
+            suffix += 1;
+        }
+        candidate
+    }
+
+    pub fn update_profile(&mut self, peer_id: PeerId, profile: Profile) -> Res {
+        let nickname = match validate_nickname(&profile.nickname) {
+            Some(nickname) => self.unique_nickname(peer_id, &nickname),
+            None => bail!(OutputError::InvalidNickname),
+        };
+        let (peer, status) = self.connected.get_mut(&peer_id).context("The user is not connected and attempts to update its profile")?;
+        info!("User: {} renamed from {} to {}", peer_id, peer.pseudo, nickname);
+        peer.pseudo = nickname;
+        peer.color = profile.color;
+        let updated = peer.clone();
+        // Sessions keep their own copies of the peers
+        if let PeerStatus::InSession(session_id) = status {
+            if let Some(session) = self.sessions.get_mut(session_id) {
+                session.update_peer(updated.clone());
+            }
+        }
+        updated.send(Output::Profile(PeerDTO::from(&updated)))?;
+        self.broadcast_delta(HubDelta::PeerUpdated(PeerDTO::from(&updated)));
+        OK
+    }
+
+    /// Gives the channel of the new connection to the detached peer.
+    fn resume(&mut self, peer: Peer) -> &Peer {
+        let id = peer.id;
+        self.detached.remove(&id);
+        let (resumed, _) = self.connected.get_mut(&id).unwrap();
+        // The previous connection may still be open when the client didn't notice its loss first
+        resumed.close.try_send(()).ignore();
+        resumed.proxy_tx = peer.proxy_tx;
+        resumed.connection = peer.connection;
+        resumed.close = peer.close;
+        info!("User: {} resumed its connection", id);
+        resumed
+    }
+
+    /// Puts the resumed peer back in its session, which catches it up.
+    fn rejoin_session(&mut self, peer_id: PeerId) {
+        if let Some((peer, PeerStatus::InSession(session_id))) = self.connected.get(&peer_id) {
+            if let Some(session) = self.sessions.get_mut(session_id) {
+                session.add_peer(peer.clone());
+            }
+        }
+    }
+
+    /// Keeps the peer and its place in its session until it resumes or the grace period ends.
+    pub fn detach(&mut self, peer_id: PeerId) {
+        let status = match self.connected.get(&peer_id) {
+            Some((_, status)) => status,
+            None => return warn!("The user {} is not connected and tries to disconnect", peer_id),
+        };
+        info!("User: {} lost its connection, kept for {:?}", peer_id, self.config.resume_grace());
+        if let PeerStatus::InSession(session_id) = status {
+            if let Some(session) = self.sessions.get_mut(session_id) {
+                session.detach_peer(peer_id);
+            }
+        }
+        self.detached.insert(peer_id, Instant::now());
+    }
+
+    fn expire_detached(&mut self) {
+        let grace = self.config.resume_grace();
+        let expired: Vec<PeerId> = self.detached.iter()
+            .filter(|(_, since)| since.elapsed() > grace)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in expired {
+            self.disconnect(peer_id);
+        }
+    }
+
+    /// Tells the peers about the sessions whose task ended since the last check.
+    fn reap_sessions(&mut self) {
+        let reaped: Vec<SessionId> = self.sessions.values_mut()
+            .filter_map(|session| if session.reap() { Some(session.id()) } else { None })
+            .collect();
+        for session_id in reaped {
+            self.session_updated(session_id);
+        }
+    }
+
+    fn can_resume(&self, resume: &Resume) -> bool {
+        self.resume_tokens.get(&resume.peer) == Some(&resume.token)
+    }
+
     pub fn disconnect(&mut self, peer_id: PeerId) {
         info!("User: {} disconnected", peer_id);
-        self.connected.remove(&peer_id)
-            .expect("The user is not connected and tries to disconnect")
-            .pipe(|(_, status)| if let PeerStatus::InSession(session_id) = status {
-                self.sessions.get_mut(&session_id).unwrap().rm_peer(peer_id);
-            });
-        self.connected.remove(&peer_id);
+        self.detached.remove(&peer_id);
+        self.resume_tokens.remove(&peer_id);
+        match self.connected.remove(&peer_id) {
+            Some((_, PeerStatus::InSession(session_id))) => self.remove_from_session(peer_id, session_id),
+            Some((_, PeerStatus::Idle)) => ignore(),
+            None => return warn!("The user {} is not connected and tries to disconnect", peer_id),
+        }
+        self.broadcast_delta(HubDelta::PeerDisconnected(peer_id));
     }
 
     pub fn send_specific(&mut self, _peer_id: PeerId) {
@@ -123,110 +405,532 @@ impl Hub {
         match action {
             LogInAction::Connected(peer) => {
                 let id = peer.id;
-                self.connect(peer).send(Output::Connected(id))?;
+                // The connection checked the resume token before taking the id
+                let resumed = self.connected.contains_key(&id);
+                let invalid_nickname = !resumed && !peer.pseudo.is_empty() && validate_nickname(&peer.pseudo).is_none();
+                let resume_token = ResumeToken::new_v4();
+                self.resume_tokens.insert(id, resume_token);
+                let peer = if resumed { self.resume(peer) } else { self.connect(peer) };
+                peer.send(Output::Connected { id, resume_token, resumed })?;
+                peer.send(Output::Profile(PeerDTO::from(peer)))?;
+                if invalid_nickname {
+                    peer.send(Output::Error(OutputError::InvalidNickname))?;
+                }
+                self.send_state(id)?;
+                // After the state, the catch up of the session must not come before the connection
+                if resumed {
+                    self.rejoin_session(id);
+                }
                 OK
             }
-            LogInAction::Disconnect(user_id) => {
-                self.disconnect(user_id);
+            LogInAction::Resume(resume, answer) => {
+                answer.send(self.can_resume(&resume)).ignore();
+                OK
+            }
+            LogInAction::Disconnect(user_id, connection) => {
+                match self.connected.get(&user_id) {
+                    Some((peer, _)) if peer.connection == connection => self.detach(user_id),
+                    // A resumed connection replaced the closed one
+                    Some(_) => debug!("Old connection of {} closed", user_id),
+                    None => warn!("The user {} is not connected and tries to disconnect", user_id),
+                }
                 OK
             }
         }
     }
 
-    pub fn handle_hub_action(&mut self, from: PeerId, action: HubAction) -> Res {
+    pub fn handle_hub_action(&mut self, from: PeerId, action: HubAction) -> Res<Ack> {
         match action {
-            HubAction::CreateSession(password) => {
-                let created_session = self.create_session(from, "Test".to_string(), password.clone());
-                self.join_session(from, created_session, &password)?;
-                info!("Created new Session from the initiative of {}", from);
-                OK
-            }
-            HubAction::Join(session_id, session_password) => {
-                self.join_session(from, session_id, &session_password)?;
-                info!("User: {}, joined {}", from, session_id);
-                OK
-            }
             HubAction::SessionStart => {
                 self.start_session(from)
             }
+            HubAction::Share(media) => {
+                self.share_media(from, media)
+            }
+            HubAction::Leave => {
+                self.leave_session(from)
+            }
+            HubAction::UpdateProfile(profile) => {
+                self.update_profile(from, profile)
+            }
+            HubAction::SetControlPolicy(policy) => {
+                self.change_permissions(from, |session| session.set_policy(policy))
+            }
+            HubAction::SetController(peer_id, controller) => {
+                if controller {
+                    let session = self.get_session(from).context("The peer didn't join any session")?;
+                    ensure!(session.contains_peer(peer_id), "The new controller {} is not in the session", peer_id);
+                }
+                self.change_permissions(from, |session| session.set_controller(peer_id, controller))
+            }
+            HubAction::TransferOwnership(peer_id) => {
+                let session = self.get_session(from).context("The peer didn't join any session")?;
+                ensure!(session.contains_peer(peer_id), "The new owner {} is not in the session", peer_id);
+                self.change_permissions(from, |session| session.set_owner(peer_id))
+            }
+            HubAction::Resync => {
+                self.send_state(from)
+            }
             _ => {
                 warn!("Action not handled");
-                OK
+                bail!(OutputError::InvalidRequest)
             }
-        }
+        }?;
+        Ok(Ack::Done)
     }
 
-    pub fn handle_net_input(&mut self, input: Input) -> Res {
-        match input.action {
+    /// Applies the input and answers its request, the errors meant for the peer are sent back to
+    /// it even without request.
+    pub fn handle_net_input(&mut self, from: PeerId, request: Option<RequestId>, action: InputAction) -> Res {
+        let result = match action {
+            // Answered once the password is checked off the hub thread
+            InputAction::HubAction(HubAction::CreateSession(name, password)) => {
+                return self.hash_then_create(from, request, name, password);
+            }
+            InputAction::HubAction(HubAction::Join(session_id, password)) => {
+                return self.verify_then_join(from, request, session_id, password);
+            }
             InputAction::SessionAction(session_action) => {
-                self.handle_session_action(input.from.unwrap(), session_action)
+                self.handle_session_action(from, session_action).map(|_| Ack::Done)
             }
             InputAction::HubAction(hub_action) => {
-                self.handle_hub_action(input.from.unwrap(), hub_action)
+                self.handle_hub_action(from, hub_action)
             }
-            _ => { todo!("AZD") }
+            InputAction::SyncReport(report) => {
+                self.relay_report(from, report);
+                Ok(Ack::Done)
+            }
+            InputAction::Ping(_) => {
+                warn!("Connection input {:?} from {} reached the hub, ignored", action, from);
+                Err(OutputError::InvalidRequest.into())
+            }
+        };
+        self.answer(from, request, result)
+    }
+
+    /// Sends the answer of the request, the errors meant for the peer are sent back to it even
+    /// without request.
+    fn answer(&self, from: PeerId, request: Option<RequestId>, result: Res<Ack>) -> Res {
+        let answer = match (request, &result) {
+            (Some(request), Ok(ack)) => Some(Output::Ack(request, *ack)),
+            (Some(request), Err(e)) => {
+                let error = e.downcast_ref::<OutputError>().copied().unwrap_or(OutputError::InvalidRequest);
+                Some(Output::Refused(request, error))
+            }
+            (None, Err(e)) => e.downcast_ref::<OutputError>().map(|error| Output::Error(*error)),
+            (None, Ok(_)) => None,
+        };
+        if let (Some(answer), Some((peer, _))) = (answer, self.connected.get(&from)) {
+            peer.send(answer)?;
         }
+        result.map(|_| ())
+    }
+
+    /// Hashes the password on the blocking threads of the runtime, argon2 is slow by design. The
+    /// session is created when the hash comes back.
+    fn hash_then_create(&mut self, from: PeerId, request: Option<RequestId>, name: String, password: String) -> Res {
+        if let Err(e) = self.check_new_session(from, &name) {
+            return self.answer(from, request, Err(e));
+        }
+        self.check_off_thread(from, request, move || PasswordOutcome::Hashed(name, hash_password(&password)))
+    }
+
+    /// Verifies the password on the blocking threads of the runtime, the session is joined when
+    /// it matches.
+    fn verify_then_join(&mut self, from: PeerId, request: Option<RequestId>, session_id: SessionId, password: String) -> Res {
+        let hash = match self.sessions.get(&session_id) {
+            Some(session) => session.password_hash().map(str::to_string),
+            None => return self.answer(from, request, Err(OutputError::UnknownSession.into())),
+        };
+        self.check_off_thread(from, request, move || {
+            PasswordOutcome::Verified(session_id, verify_password(hash.as_deref(), &password))
+        })
+    }
+
+    /// Runs the check unless the peer already waits for one or too many run, the request is then
+    /// refused with `LimitReached`.
+    fn check_off_thread(&mut self, from: PeerId, request: Option<RequestId>, check: impl FnOnce() -> PasswordOutcome + Send + 'static) -> Res {
+        if self.checking.contains(&from) || self.checking.len() >= MAX_PASSWORD_CHECKS {
+            warn!("Password check of {} refused, {} checks running", from, self.checking.len());
+            return self.answer(from, request, Err(OutputError::LimitReached.into()));
+        }
+        self.checking.insert(from);
+        let checks = self.checks_tx.clone();
+        self.handle_runtime.spawn_blocking(move || {
+            // The hub may be stopped meanwhile
+            checks.send(PasswordCheck { from, request, outcome: check() }).ignore();
+        });
+        OK
+    }
+
+    /// Completes the creation or the join once the password is checked.
+    fn password_checked(&mut self, check: PasswordCheck) -> Res {
+        let PasswordCheck { from, request, outcome } = check;
+        self.checking.remove(&from);
+        if !self.connected.contains_key(&from) {
+            debug!("{} disconnected before the check of its password", from);
+            return OK;
+        }
+        let result = match outcome {
+            PasswordOutcome::Hashed(name, hash) => hash
+                .and_then(|hash| self.create_session(from, &name, hash))
+                .and_then(|session_id| {
+                    self.enter_session(from, session_id)?;
+                    info!("Created new Session from the initiative of {}", from);
+                    Ok(Ack::SessionCreated(session_id))
+                }),
+            PasswordOutcome::Verified(session_id, true) => {
+                self.join_session(from, session_id).map(|_| {
+                    info!("User: {}, joined {}", from, session_id);
+                    Ack::Done
+                })
+            }
+            PasswordOutcome::Verified(_, false) => Err(OutputError::PasswordDoesntMatch.into()),
+        };
+        self.answer(from, request, result)
     }
 
     pub fn handle_session_action(&mut self, from: PeerId, input: PlayerAction) -> Res {
         let session = self.get_mut_session(from)
             .context("The peer didn't join any session")?;
-        session.handle_action(input)?;
+        if !session.can_control(from) {
+            bail!(OutputError::Unauthorized);
+        }
+        session.handle_action(from, input)?;
         OK
     }
 
+    /// Shares the playback reported by the peer with the participants of its session.
+    fn relay_report(&self, from: PeerId, report: SyncReport) {
+        // A report can cross the leave of its peer
+        let session = match self.get_session(from) {
+            Some(session) => session,
+            None => return trace!("Sync report of {} outside a session, dropped", from),
+        };
+        for participant in session.participants() {
+            if let Err(e) = participant.send(Output::PeerSync(from, report)) {
+                warn!("Couldn't send the sync report of {} to {}, error: {}", from, participant.id, e);
+            }
+        }
+    }
+
     pub fn handle(&mut self, message: HubMessage) -> Res {
         match message {
-            HubMessage::NetInput(input) => {
-                self.handle_net_input(input)
+            HubMessage::NetInput(from, connection, request, action) => {
+                match self.connected.get(&from) {
+                    Some((peer, _)) if peer.connection == connection => self.handle_net_input(from, request, action),
+                    // Sent on a connection taken over by a resumed one
+                    _ => {
+                        debug!("Input {:?} of {} on a previous connection, ignored", action, from);
+                        OK
+                    }
+                }
             }
             HubMessage::LogInAction(action) => {
                 self.handle_login_action(action)
             }
+            HubMessage::Shutdown => {
+                warn!("Shutdown handled outside the run loop, ignored");
+                OK
+            }
         }
     }
 
-    pub fn get_peer(&self, peer_id: PeerId) -> &Peer {
-        self.connected.get(&peer_id).map(|s| &s.0).unwrap()
+    pub fn get_peer(&self, peer_id: PeerId) -> Option<&Peer> {
+        self.connected.get(&peer_id).map(|s| &s.0)
     }
 
     pub fn get_session(&self, peer_id: PeerId) -> Option<&Session> {
-        match self.connected.get(&peer_id).unwrap().1 {
+        match self.connected.get(&peer_id)?.1 {
             PeerStatus::InSession(ses) => self.sessions.get(&ses),
             PeerStatus::Idle => None
         }
     }
 
     pub fn get_mut_session(&mut self, peer_id: PeerId) -> Option<&mut Session> {
-        match self.connected.get(&peer_id).expect("The user is not connected").1 {
+        match self.connected.get(&peer_id)?.1 {
             PeerStatus::InSession(ses) => self.sessions.get_mut(&ses),
             PeerStatus::Idle => None
         }
     }
 
 
-    pub fn send_new_state_to_peers(&self) -> Res {
-        for (_, (peer, _)) in self.connected.iter() {
-            let hub_state = self.create_hub_state(peer);
-            peer.send(Output::World(hub_state))?;
+    /// Sends the delta, numbered after the previous one, to every connected peer.
+    fn broadcast_delta(&mut self, delta: HubDelta) {
+        self.seq += 1;
+        // The detached peers receive a snapshot when they resume
+        for (peer, _) in self.connected.values().filter(|(p, _)| !self.detached.contains_key(&p.id)) {
+            if let Err(e) = peer.send(Output::HubDelta { seq: self.seq, delta: delta.clone() }) {
+                warn!("Couldn't send the hub delta {} to {}, error: {}", self.seq, peer.id, e);
+            }
         }
-        OK
+    }
+
+    fn session_updated(&mut self, session_id: SessionId) {
+        if let Some(session) = self.sessions.get(&session_id) {
+            let session = SessionDTO::with_participants(session);
+            self.broadcast_delta(HubDelta::SessionUpdated(session));
+        }
+    }
+
+    /// Sends the full hub state to the peer, the next deltas follow its sequence number.
+    pub fn send_state(&self, peer_id: PeerId) -> Res {
+        let peer = self.get_peer(peer_id).context("The user is not connected and asks for the hub state")?;
+        peer.send(Output::World(self.create_hub_state(peer)))
     }
 
     pub fn create_hub_state(&self, peer: &Peer) -> HubState {
         let peer_id = peer.id;
         HubState {
+            seq: self.seq,
             me: peer.into(),
-            sessions: self.sessions.iter().map(|(_, s)| s).map(SessionDTO::from).collect(),
+            sessions: self.sessions.values().map(SessionDTO::with_participants).collect(),
             connected: self.connected.iter().map(|(_, (peer, _))| PeerDTO::from(peer)).collect(),
-            my_session: self.get_session(peer_id).map(SessionDTO::from),
+            my_session: self.get_session(peer_id).map(SessionDTO::with_participants),
         }
     }
+}
 
-    pub fn handle_error(&self, peer_id: PeerId, output_error: OutputError) -> Res {
-        self.get_peer(peer_id).send(Output::Error(output_error))?;
-        Err(anyhow::anyhow!("{:?}", output_error))
+const MAX_NICKNAME_LENGTH: usize = 24;
+const MAX_SESSION_NAME_LENGTH: usize = 48;
+
+/// Returns the trimmed nickname if it is usable.
+fn validate_nickname(nickname: &str) -> Option<String> {
+    validate_name(nickname, MAX_NICKNAME_LENGTH)
+}
+
+fn validate_session_name(name: &str) -> Option<String> {
+    validate_name(name, MAX_SESSION_NAME_LENGTH)
+}
+
+fn validate_name(name: &str, max_length: usize) -> Option<String> {
+    let name = name.trim();
+    let length = name.chars().count();
+    if length == 0 || length > max_length || name.chars().any(char::is_control) {
+        None
+    } else {
+        Some(name.to_string())
     }
 }
 
+fn guest_nickname(peer_id: PeerId) -> String {
+    format!("guest-{}", &peer_id.to_simple().to_string()[..6])
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use uuid::Uuid;
+
+    use crate::server::net_proto::ControlPolicy;
+
+    use super::*;
+
+    fn hub(runtime: &Runtime) -> Hub {
+        Hub::new(unbounded().1, runtime.handle().clone(), ServerConfig::default())
+    }
+
+    /// Connects a peer, its outputs are received on the returned receiver.
+    fn connect(hub: &mut Hub, nickname: &str) -> (Peer, Receiver<Output>) {
+        let (proxy_tx, outputs) = unbounded();
+        let peer = Peer {
+            id: PeerId::new_v4(),
+            pseudo: nickname.to_string(),
+            color: None,
+            connection: Uuid::new_v4(),
+            proxy_tx,
+            close: unbounded().0,
+        };
+        hub.connected.insert(peer.id, (peer.clone(), PeerStatus::Idle));
+        (peer, outputs)
+    }
+
+    /// Creates a session of the first peer, the others join it.
+    fn session_of(hub: &mut Hub, peers: &[&Peer]) -> SessionId {
+        let session = hub.create_session(peers[0].id, "movie night", None).unwrap();
+        for peer in peers {
+            hub.join_session(peer.id, session).unwrap();
+        }
+        session
+    }
+
+    fn refusal(result: Res<Ack>) -> Option<OutputError> {
+        result.err()?.downcast_ref::<OutputError>().copied()
+    }
+
+    #[test]
+    fn validates_nicknames() {
+        assert_eq!(validate_nickname("  alice "), Some("alice".to_string()));
+        assert_eq!(validate_nickname("ünïcødé"), Some("ünïcødé".to_string()));
+        assert_eq!(validate_nickname(&"é".repeat(MAX_NICKNAME_LENGTH)), Some("é".repeat(MAX_NICKNAME_LENGTH)));
+        assert_eq!(validate_nickname(&"a".repeat(MAX_NICKNAME_LENGTH + 1)), None);
+        assert_eq!(validate_nickname("   "), None);
+        assert_eq!(validate_nickname("bad\nname"), None);
+    }
+
+    #[test]
+    fn validates_session_names() {
+        assert_eq!(validate_session_name(" movie night "), Some("movie night".to_string()));
+        assert_eq!(validate_session_name(&"a".repeat(MAX_SESSION_NAME_LENGTH + 1)), None);
+        assert_eq!(validate_session_name(""), None);
+    }
+
+    #[test]
+    fn suffixes_the_nicknames_taken() {
+        let runtime = Runtime::new().unwrap();
+        let mut hub = hub(&runtime);
+        let alice = connect(&mut hub, "alice").0.id;
+        connect(&mut hub, "Alice#2");
+
+        assert_eq!(hub.unique_nickname(alice, "alice"), "alice");
+        assert_eq!(hub.unique_nickname(PeerId::new_v4(), "bob"), "bob");
+        assert_eq!(hub.unique_nickname(PeerId::new_v4(), "ALICE"), "ALICE#3");
+    }
+
+    #[test]
+    fn ignores_the_inputs_of_a_previous_connection() {
+        let runtime = Runtime::new().unwrap();
+        let mut hub = hub(&runtime);
+        let (peer, outputs) = connect(&mut hub, "alice");
+        let resync = |connection| HubMessage::NetInput(peer.id, connection, Some(1), InputAction::HubAction(HubAction::Resync));
+
+        hub.handle(resync(Uuid::new_v4())).unwrap();
+        assert!(outputs.is_empty());
+        hub.handle(resync(peer.connection)).unwrap();
+        assert!(matches!(outputs.try_recv(), Ok(Output::World(_))));
+        assert!(matches!(outputs.try_recv(), Ok(Output::Ack(1, Ack::Done))));
+    }
+
+    #[test]
+    fn ignores_the_disconnection_of_a_previous_connection() {
+        let runtime = Runtime::new().unwrap();
+        let mut hub = hub(&runtime);
+        let (peer, _outputs) = connect(&mut hub, "alice");
+
+        hub.handle_login_action(LogInAction::Disconnect(peer.id, Uuid::new_v4())).unwrap();
+        assert!(!hub.detached.contains_key(&peer.id));
+        hub.handle_login_action(LogInAction::Disconnect(peer.id, peer.connection)).unwrap();
+        assert!(hub.detached.contains_key(&peer.id));
+        assert!(hub.connected.contains_key(&peer.id));
+    }
+
+    #[test]
+    fn reserves_the_permissions_to_the_owner() {
+        let runtime = Runtime::new().unwrap();
+        let mut hub = hub(&runtime);
+        let (owner, _owner_outputs) = connect(&mut hub, "owner");
+        let (guest, _guest_outputs) = connect(&mut hub, "guest");
+        session_of(&mut hub, &[&owner, &guest]);
+
+        let actions = [
+            HubAction::SetControlPolicy(ControlPolicy::Restricted),
+            HubAction::SetController(guest.id, true),
+            HubAction::TransferOwnership(guest.id),
+        ];
+        for action in actions {
+            assert_eq!(refusal(hub.handle_hub_action(guest.id, action)), Some(OutputError::NotOwner));
+        }
+    }
+
+    #[test]
+    fn restricted_sessions_are_controlled_by_the_owner_and_the_controllers() {
+        let runtime = Runtime::new().unwrap();
+        let mut hub = hub(&runtime);
+        let (owner, _owner_outputs) = connect(&mut hub, "owner");
+        let (guest, _guest_outputs) = connect(&mut hub, "guest");
+        session_of(&mut hub, &[&owner, &guest]);
+        let share = || HubAction::Share("movie.mkv".to_string());
+
+        hub.handle_hub_action(owner.id, HubAction::SetControlPolicy(ControlPolicy::Restricted)).unwrap();
+        assert_eq!(refusal(hub.handle_hub_action(guest.id, share())), Some(OutputError::Unauthorized));
+        assert_eq!(refusal(hub.handle_hub_action(guest.id, HubAction::SessionStart)), Some(OutputError::Unauthorized));
+        hub.handle_hub_action(owner.id, share()).unwrap();
+
+        hub.handle_hub_action(owner.id, HubAction::SetController(guest.id, true)).unwrap();
+        hub.handle_hub_action(guest.id, share()).unwrap();
+        hub.handle_hub_action(owner.id, HubAction::SetController(guest.id, false)).unwrap();
+        assert_eq!(refusal(hub.handle_hub_action(guest.id, share())), Some(OutputError::Unauthorized));
+    }
+
+    #[test]
+    fn refuses_the_permissions_of_peers_outside_the_session() {
+        let runtime = Runtime::new().unwrap();
+        let mut hub = hub(&runtime);
+        let (owner, _owner_outputs) = connect(&mut hub, "owner");
+        let (stranger, _stranger_outputs) = connect(&mut hub, "stranger");
+        let session = session_of(&mut hub, &[&owner]);
+        hub.handle_hub_action(owner.id, HubAction::SetControlPolicy(ControlPolicy::Restricted)).unwrap();
+
+        assert!(hub.handle_hub_action(owner.id, HubAction::SetController(stranger.id, true)).is_err());
+        assert!(hub.handle_hub_action(owner.id, HubAction::TransferOwnership(stranger.id)).is_err());
+        assert_eq!(hub.sessions[&session].owner(), owner.id);
+        assert!(!hub.sessions[&session].can_control(stranger.id));
+    }
+
+    #[test]
+    fn hands_the_ownership_over() {
+        let runtime = Runtime::new().unwrap();
+        let mut hub = hub(&runtime);
+        let (owner, _owner_outputs) = connect(&mut hub, "owner");
+        let (guest, _guest_outputs) = connect(&mut hub, "guest");
+        let (controller, _controller_outputs) = connect(&mut hub, "controller");
+        let session = session_of(&mut hub, &[&owner, &guest, &controller]);
 
+        hub.handle_hub_action(owner.id, HubAction::TransferOwnership(guest.id)).unwrap();
+        assert_eq!(hub.sessions[&session].owner(), guest.id);
+        let policy = || HubAction::SetControlPolicy(ControlPolicy::Restricted);
+        assert_eq!(refusal(hub.handle_hub_action(owner.id, policy())), Some(OutputError::NotOwner));
+        hub.handle_hub_action(guest.id, policy()).unwrap();
+
+        // The controllers are elected first when the owner leaves
+        hub.handle_hub_action(guest.id, HubAction::SetController(controller.id, true)).unwrap();
+        hub.leave_session(guest.id).unwrap();
+        assert_eq!(hub.sessions[&session].owner(), controller.id);
+    }
+
+    #[test]
+    fn refuses_the_inputs_of_unknown_peers() {
+        let runtime = Runtime::new().unwrap();
+        let mut hub = hub(&runtime);
+        let actions = [
+            InputAction::SessionAction(PlayerAction::Play),
+            InputAction::HubAction(HubAction::Resync),
+            InputAction::HubAction(HubAction::SessionStart),
+            InputAction::HubAction(HubAction::Share("media.mkv".to_string())),
+            InputAction::HubAction(HubAction::TransferOwnership(PeerId::new_v4())),
+            InputAction::HubAction(HubAction::SetControlPolicy(ControlPolicy::Restricted)),
+        ];
+        for action in actions {
+            assert!(hub.handle_net_input(PeerId::new_v4(), Some(1), action).is_err());
+        }
+    }
+
+    #[test]
+    fn limits_the_password_checks_running() {
+        let runtime = Runtime::new().unwrap();
+        let mut hub = hub(&runtime);
+        let (owner, _) = connect(&mut hub, "owner");
+        let session = hub.create_session(owner.id, "movie night", None).unwrap();
+        // Checks on a public session are immediate, they keep their place until the hub completes them
+        let join = || InputAction::HubAction(HubAction::Join(session, String::new()));
+        let refused = |outputs: &Receiver<Output>, request| matches!(outputs.try_recv(), Ok(Output::Refused(r, OutputError::LimitReached)) if r == request);
+
+        let (peer, outputs) = connect(&mut hub, "alice");
+        hub.handle_net_input(peer.id, Some(1), join()).unwrap();
+        assert!(hub.handle_net_input(peer.id, Some(2), join()).is_err());
+        assert!(refused(&outputs, 2));
+
+        let others: Vec<_> = (1..MAX_PASSWORD_CHECKS).map(|_| connect(&mut hub, "bob")).collect();
+        for (peer, _) in &others {
+            hub.handle_net_input(peer.id, Some(1), join()).unwrap();
+        }
+        let (peer, outputs) = connect(&mut hub, "carol");
+        assert!(hub.handle_net_input(peer.id, Some(1), join()).is_err());
+        assert!(refused(&outputs, 1));
+
+        // A check done frees its place
+        let check = hub.checks.recv_timeout(Duration::from_secs(10)).unwrap();
+        hub.password_checked(check).unwrap();
+        hub.handle_net_input(peer.id, Some(2), join()).unwrap();
+    }
+}
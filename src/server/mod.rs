@@ -1,5 +1,6 @@
 
 use flume::Sender;
+use anyhow::Context;
 use futures::TryStreamExt;
 use log::*;
 use tokio::net::tcp::OwnedReadHalf;
@@ -7,10 +8,10 @@ use tokio::time::Duration;
 use tokio::time::timeout;
 use uuid::Uuid;
 
-use util::NetReader;
+use util::{NetReader, now_micros};
 
 use crate::server::actor_proto::{HubMessage, SessionMessage};
-use crate::server::net_proto::{Input, InputAction, Output};
+use crate::server::net_proto::{Input, InputAction, Output, OutputError};
 
 pub mod net_proto;
 pub mod util;
@@ -18,9 +19,10 @@ pub mod peer;
 pub mod session;
 pub mod actor_proto;
 pub mod hub;
+pub mod config;
+pub mod daemon;
 
-
-const REFRESH_TICK: u64 = 40;
+pub use daemon::{Server, ServerBuilder};
 
 pub type PeerMessage = Output;
 
@@ -37,19 +39,32 @@ pub type PeerId = Uuid;
 pub struct PeerEventReader {
     pub net_reader: NetReader<Input, OwnedReadHalf>,
     pub hub_tx: HubTransmitter,
+    pub peer_tx: PeerTransmitter,
+    pub connection: Uuid,
 }
 
 impl PeerEventReader {
     pub async fn run(&mut self, duration: Duration, peer_id: PeerId) -> Res {
         loop {
-            if let Some(input) = timeout(duration, self.net_reader.try_next()).await?? {
-                // We ignore alive packets they serve only to reset timeout
+            let input = timeout(duration, self.net_reader.try_next()).await??
+                .context("The peer closed the connection")?;
+            let server_received = now_micros();
+            // Pings are answered here to not add the hub latency to the measure, they also reset the timeout
+            if let InputAction::Ping(client_sent) = input.action {
+                trace!("Ping received from : {}", peer_id);
+                self.peer_tx.send_async(Output::Pong { client_sent, server_received, server_sent: now_micros() }).await?
+            } else {
                 debug!("Event reader of peer_id: {} received: {:?}", peer_id, input);
-                if input.action != InputAction::Alive {
-                    assert_eq!(input.from.unwrap(), peer_id);
-                    self.hub_tx.send_async(HubMessage::NetInput(input)).await?
-                } else {
-                    trace!("Alive received from : {}", peer_id)
+                match input.from {
+                    Some(from) if from != peer_id => {
+                        warn!("Peer {} sent an input as {}, ignored", peer_id, from);
+                        let answer = match input.request {
+                            Some(request) => Output::Refused(request, OutputError::ForgedIdentity),
+                            None => Output::Error(OutputError::ForgedIdentity),
+                        };
+                        self.peer_tx.send_async(answer).await?
+                    }
+                    _ => self.hub_tx.send_async(HubMessage::NetInput(peer_id, self.connection, input.request, input.action)).await?
                 }
             }
         }
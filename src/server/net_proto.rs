@@ -3,40 +3,166 @@ use crate::server::{PeerId, SessionId};
 use crate::server::session::{Session};
 use crate::server::peer::Peer;
 
+/// Version of the protocol, peers must speak the same one.
+pub const PROTOCOL_VERSION: u32 = 9;
+
+/// Optional features understood by this build, the ones used are the ones both sides understand.
+pub const FEATURES: &[&str] = &["clock-sync", "catch-up", "permissions", "profiles", "hub-deltas", "request-ids", "resume", "sync-reports"];
+
+/// Chosen by the client to match the answer of the server with its request.
+pub type RequestId = u64;
+
+/// Secret given at the connection to resume it after a network failure.
+pub type ResumeToken = uuid::Uuid;
+
 #[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
 pub struct Input {
+    /// Not needed, the server knows the peer of each connection. An id different from the one of
+    /// the connection is rejected.
     pub from: Option<PeerId>,
+    /// When set, the server answers with an `Output::Ack` or an `Output::Refused` carrying it.
+    pub request: Option<RequestId>,
     pub action: InputAction,
 }
 
+impl Input {
+    pub fn new(action: InputAction) -> Self {
+        Input { from: None, request: None, action }
+    }
+
+    pub fn request(request: RequestId, action: InputAction) -> Self {
+        Input { from: None, request: Some(request), action }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
 pub enum InputAction {
     HubAction(HubAction),
     SessionAction(PlayerAction),
-    Connect,
-    Alive,
+    /// Keeps the connection alive and carries the client send time (µs) for the clock synchronisation.
+    Ping(u64),
+    /// Playback of the local player, relayed to the participants of the session.
+    SyncReport(SyncReport),
 }
 
-#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+/// Playback of a participant as measured by its client.
+#[derive(Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct SyncReport {
+    /// Position of the player in ms.
+    pub position: u64,
+    /// Last drift from the session in ms, positive when the player is ahead.
+    pub drift: i64,
+    pub playing: bool,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Debug)]
 pub enum PlayerAction {
     Play,
     Pause,
     Stop,
+    /// Position to jump to, in milliseconds from the start of the media.
+    Seek(u64),
 }
 
 #[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
 pub enum HubAction {
-    CreateSession(String),
+    /// Name and password of the session.
+    CreateSession(String, String),
     Share(String),
     Join(SessionId, String),
     SessionStart,
+    Leave,
     Ready,
+    SetControlPolicy(ControlPolicy),
+    /// Grants or revokes the playback control to a participant.
+    SetController(PeerId, bool),
+    TransferOwnership(PeerId),
+    UpdateProfile(Profile),
+    /// Asks for a full hub state, sent by a client which missed a delta.
+    Resync,
+}
+
+/// Presentation of a client, sent after its protocol version in the handshake, see [`HelloReply`].
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct Hello {
+    pub capabilities: Vec<String>,
+    pub profile: Profile,
+    /// Previous connection to take over, the profile is then ignored.
+    pub resume: Option<Resume>,
+}
+
+impl Hello {
+    pub fn new(profile: Profile) -> Self {
+        Hello {
+            capabilities: FEATURES.iter().map(|f| f.to_string()).collect(),
+            profile,
+            resume: None,
+        }
+    }
+
+    pub fn with_resume(self, resume: Option<Resume>) -> Self {
+        Hello { resume, ..self }
+    }
+
+    /// Capabilities of the client this build understands too.
+    pub fn accepted_features(&self) -> Vec<String> {
+        self.capabilities.iter()
+            .filter(|c| FEATURES.contains(&c.as_str()))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Answer of the server to the handshake, before any `Output`.
+///
+/// The handshake is frozen so peers of any version understand each other enough to tell their
+/// versions apart: the client sends its `PROTOCOL_VERSION` alone in a frame, then its [`Hello`]
+/// which the server reads only when the versions are the same, and the server answers with this
+/// reply. Neither the version frame nor this struct may ever change.
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct HelloReply {
+    pub version: u32,
+    /// Features of the hello the server understands too.
+    pub features: Vec<String>,
+    /// Set when the server refuses the connection, it closes it after the reply.
+    pub refusal: Option<Refusal>,
+}
+
+/// Frozen with [`HelloReply`].
+#[derive(Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub enum Refusal {
+    InvalidProtocol,
+    LimitReached,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct Resume {
+    pub peer: PeerId,
+    pub token: ResumeToken,
+}
+
+/// How a peer presents itself to the others.
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug, Default)]
+pub struct Profile {
+    /// An empty nickname lets the server choose one.
+    pub nickname: String,
+    /// Avatar colour as rgb.
+    pub color: Option<[u8; 3]>,
+}
+
+/// Who can control the playback of a session, the owner always can.
+#[derive(Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub enum ControlPolicy {
+    Everyone,
+    /// Only the owner and the designated controllers.
+    Restricted,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct PeerDTO {
-    id: PeerId,
-    pseudo: String,
+    pub id: PeerId,
+    pub pseudo: String,
+    pub color: Option<[u8; 3]>,
 }
 
 impl From<&Peer> for PeerDTO{
@@ -44,17 +170,28 @@ impl From<&Peer> for PeerDTO{
         PeerDTO {
             id: p.id,
             pseudo: p.pseudo.to_string(),
+            color: p.color,
         }
     }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct SessionDTO {
-    id: SessionId,
-    name: String,
-    public: bool,
-    participants: Option<Vec<PeerDTO>>,
-    owner: PeerId
+    pub id: SessionId,
+    pub name: String,
+    pub public: bool,
+    pub started: bool,
+    pub participants: Option<Vec<PeerDTO>>,
+    pub owner: PeerId
+}
+
+impl SessionDTO {
+    pub fn with_participants(s: &Session) -> Self {
+        SessionDTO {
+            participants: Some(s.participants().map(PeerDTO::from).collect()),
+            ..SessionDTO::from(s)
+        }
+    }
 }
 
 impl From<&Session> for SessionDTO {
@@ -62,7 +199,8 @@ impl From<&Session> for SessionDTO {
         SessionDTO {
             id: s.id(),
             name: s.name().to_string(),
-            public: s.password() == "",
+            public: s.is_public(),
+            started: s.is_started(),
             participants: None,
             owner: s.owner()
         }
@@ -70,34 +208,257 @@ impl From<&Session> for SessionDTO {
 }
 
 
+/// Snapshot of the hub, the sessions carry their participants.
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct HubState {
+    /// Sequence number of the last delta included.
+    pub seq: u64,
     pub me : PeerDTO,
     pub sessions: Vec<SessionDTO>,
     pub connected: Vec<PeerDTO>,
     pub my_session: Option<SessionDTO>,
 }
 
+/// Result of a numbered delta on a [`HubState`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeltaOutcome {
+    Applied,
+    /// Already included in the state.
+    Outdated,
+    /// Deltas between the state and this one were missed, a full state is needed.
+    Gap,
+}
+
+impl HubState {
+    /// Applies the delta only if it is the one following the state.
+    pub fn apply_numbered(&mut self, seq: u64, delta: HubDelta) -> DeltaOutcome {
+        if seq <= self.seq {
+            DeltaOutcome::Outdated
+        } else if seq == self.seq + 1 {
+            self.apply(delta);
+            self.seq = seq;
+            DeltaOutcome::Applied
+        } else {
+            DeltaOutcome::Gap
+        }
+    }
+
+    pub fn apply(&mut self, delta: HubDelta) {
+        match delta {
+            HubDelta::PeerConnected(peer) => {
+                self.connected.retain(|p| p.id != peer.id);
+                self.connected.push(peer);
+            }
+            HubDelta::PeerDisconnected(peer_id) => {
+                self.connected.retain(|p| p.id != peer_id);
+            }
+            HubDelta::PeerUpdated(peer) => {
+                if peer.id == self.me.id {
+                    self.me = peer.clone();
+                }
+                let participants = self.sessions.iter_mut().filter_map(|s| s.participants.as_mut()).flatten();
+                for p in self.connected.iter_mut().chain(participants).filter(|p| p.id == peer.id) {
+                    *p = peer.clone();
+                }
+            }
+            HubDelta::SessionCreated(session) | HubDelta::SessionUpdated(session) => {
+                match self.sessions.iter_mut().find(|s| s.id == session.id) {
+                    Some(s) => *s = session,
+                    None => self.sessions.push(session),
+                }
+            }
+            HubDelta::SessionRemoved(session_id) => {
+                self.sessions.retain(|s| s.id != session_id);
+            }
+        }
+        let me = self.me.id;
+        self.my_session = self.sessions.iter()
+            .find(|s| s.participants.iter().flatten().any(|p| p.id == me))
+            .cloned();
+    }
+}
+
+/// Change of the hub, broadcast to every connected peer.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum HubDelta {
+    PeerConnected(PeerDTO),
+    PeerDisconnected(PeerId),
+    /// New profile of the peer.
+    PeerUpdated(PeerDTO),
+    SessionCreated(SessionDTO),
+    /// The session, with its participants, after a join, a leave, a start or a change of owner.
+    SessionUpdated(SessionDTO),
+    SessionRemoved(SessionId),
+}
+
+
+/// Result of a request accepted by the server.
+#[derive(Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub enum Ack {
+    Done,
+    SessionCreated(SessionId),
+}
 
 #[derive(Clone,Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum OutputError {
     PasswordDoesntMatch,
     NotConnected,
     InvalidProtocol,
+    /// The input carried the id of another peer.
+    ForgedIdentity,
+    /// The nickname is empty, too long or contains control characters.
+    InvalidNickname,
+    /// The session name is empty, too long or contains control characters.
+    InvalidSessionName,
+    /// The peer is not allowed to control the playback of the session.
+    Unauthorized,
+    /// The action is reserved to the owner of the session.
+    NotOwner,
+    /// No session has the requested id.
+    UnknownSession,
+    /// The request can't be applied in the current state, e.g. a session action outside a session.
+    InvalidRequest,
+    /// A limit of the server is reached: connections, sessions or participants of a session.
+    LimitReached,
 }
 
+impl std::fmt::Display for OutputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            OutputError::PasswordDoesntMatch => "the password doesn't match",
+            OutputError::NotConnected => "the peer is not connected",
+            OutputError::InvalidProtocol => "the protocol version is not supported",
+            OutputError::ForgedIdentity => "the input was sent as another peer",
+            OutputError::InvalidNickname => "the nickname is invalid",
+            OutputError::InvalidSessionName => "the session name is invalid",
+            OutputError::Unauthorized => "the peer can't control the playback",
+            OutputError::NotOwner => "the action is reserved to the owner of the session",
+            OutputError::UnknownSession => "the session doesn't exist",
+            OutputError::InvalidRequest => "the request can't be applied",
+            OutputError::LimitReached => "a limit of the server is reached",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for OutputError {}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum Output {
-    Connected(PeerId),
-    Joined,
-    Timestamp(u64),
+    /// Identity of the connection, `resumed` when it took over the peer of the resume token.
+    Connected { id: PeerId, resume_token: ResumeToken, resumed: bool },
+    /// Profile of the peer as accepted by the server, sent after the connection and each update.
+    Profile(PeerDTO),
+    /// The peer entered the session, after its creation or a join.
+    Joined(SessionId),
+    /// The peer left the session, sent to the remaining participants and to the peer.
+    PeerLeft(PeerId),
+    /// Session position (ms) sampled at `server_time` (µs, server clock).
+    Timestamp { position: u64, server_time: u64, playing: bool },
+    /// Sent to the participants when the owner or the control rules of the session change.
+    Permissions { owner: PeerId, policy: ControlPolicy, controllers: Vec<PeerId> },
+    /// State of the session sent to a peer joining it while it runs.
+    CatchUp { media: String, position: u64, server_time: u64, playing: bool },
+    /// Answer to a ping, every time is in µs, the client one in its clock, the server ones in the server clock.
+    Pong { client_sent: u64, server_received: u64, server_sent: u64 },
+    /// Full hub state, sent after the connection and on resync.
     World(HubState),
+    /// Delta to apply on the hub state, `seq` follows the one of the last delta or snapshot.
+    HubDelta { seq: u64, delta: HubDelta },
     Error(OutputError),
-    PlayerAction(PlayerAction)
+    /// Answer to the request of the same id.
+    Ack(RequestId, Ack),
+    /// The request of the same id was rejected.
+    Refused(RequestId, OutputError),
+    /// Action applied to the session, with the peer who initiated it.
+    PlayerAction(PeerId, PlayerAction),
+    /// Playback reported by a participant of the session, the receiver included.
+    PeerSync(PeerId, SyncReport),
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(pseudo: &str) -> PeerDTO {
+        PeerDTO { id: PeerId::new_v4(), pseudo: pseudo.to_string(), color: None }
+    }
+
+    fn session(owner: &PeerDTO, participants: Vec<PeerDTO>) -> SessionDTO {
+        SessionDTO {
+            id: SessionId::new_v4(),
+            name: "movie night".to_string(),
+            public: true,
+            started: false,
+            participants: Some(participants),
+            owner: owner.id,
+        }
+    }
+
+    fn state(me: &PeerDTO) -> HubState {
+        HubState { seq: 0, me: me.clone(), sessions: vec![], connected: vec![me.clone()], my_session: None }
+    }
 
+    #[test]
+    fn tracks_the_connected_peers() {
+        let me = peer("me");
+        let other = peer("other");
+        let mut state = state(&me);
+        state.apply(HubDelta::PeerConnected(other.clone()));
+        state.apply(HubDelta::PeerConnected(other.clone()));
+        assert_eq!(state.connected.len(), 2);
+        state.apply(HubDelta::PeerDisconnected(other.id));
+        assert_eq!(state.connected.iter().map(|p| p.id).collect::<Vec<_>>(), vec![me.id]);
+    }
 
+    #[test]
+    fn renames_the_peer_everywhere() {
+        let me = peer("me");
+        let mut state = state(&me);
+        state.apply(HubDelta::SessionCreated(session(&me, vec![me.clone()])));
+        let renamed = PeerDTO { pseudo: "renamed".to_string(), ..me.clone() };
+        state.apply(HubDelta::PeerUpdated(renamed));
+        assert_eq!(state.me.pseudo, "renamed");
+        assert_eq!(state.connected[0].pseudo, "renamed");
+        assert_eq!(state.sessions[0].participants.as_ref().unwrap()[0].pseudo, "renamed");
+        assert_eq!(state.my_session.unwrap().participants.unwrap()[0].pseudo, "renamed");
+    }
 
+    #[test]
+    fn follows_the_session_of_the_peer() {
+        let me = peer("me");
+        let other = peer("other");
+        let mut state = state(&me);
+        let created = session(&other, vec![other.clone()]);
+        state.apply(HubDelta::SessionCreated(created.clone()));
+        assert!(state.my_session.is_none());
 
+        let joined = SessionDTO { participants: Some(vec![other.clone(), me.clone()]), ..created.clone() };
+        state.apply(HubDelta::SessionUpdated(joined));
+        assert_eq!(state.sessions.len(), 1);
+        assert_eq!(state.my_session.as_ref().map(|s| s.id), Some(created.id));
+
+        state.apply(HubDelta::SessionRemoved(created.id));
+        assert!(state.sessions.is_empty());
+        assert!(state.my_session.is_none());
+    }
 
+    #[test]
+    fn applies_only_the_next_delta() {
+        let me = peer("me");
+        let mut state = state(&me);
+        state.seq = 4;
+        let delta = || HubDelta::PeerConnected(peer("other"));
+        assert_eq!(state.apply_numbered(3, delta()), DeltaOutcome::Outdated);
+        assert_eq!(state.apply_numbered(4, delta()), DeltaOutcome::Outdated);
+        assert_eq!(state.connected.len(), 1);
+
+        assert_eq!(state.apply_numbered(5, delta()), DeltaOutcome::Applied);
+        assert_eq!(state.seq, 5);
+        assert_eq!(state.connected.len(), 2);
+
+        assert_eq!(state.apply_numbered(7, delta()), DeltaOutcome::Gap);
+        assert_eq!(state.seq, 5);
+        assert_eq!(state.connected.len(), 2);
+    }
+}
@@ -4,16 +4,21 @@ use uuid::Uuid;
 
 use crate::server::util::NetWriter;
 use crate::server::net_proto::{Output};
-use crate::server::{HubTransmitter, PeerMessage, PeerTransmitter, Res, OK};
+use crate::server::{PeerMessage, PeerTransmitter, Res, OK};
 use futures::{SinkExt};
-use flume::Receiver;
+use flume::{Receiver, Sender};
 
 
 #[derive(Debug, Clone)]
 pub struct Peer {
     pub id: Uuid,
     pub pseudo: String,
+    pub color: Option<[u8; 3]>,
+    /// Identifies the connection behind `proxy_tx`, a resumed peer gets the one of its new connection.
+    pub connection: Uuid,
     pub proxy_tx: PeerTransmitter,
+    /// Closes the connection, when a resumed connection takes over the peer.
+    pub close: Sender<()>,
 }
 
 impl Peer {
@@ -30,16 +35,14 @@ impl Peer {
 
 #[derive(Debug)]
 pub struct PeerProxy {
-    hub_tx: HubTransmitter,
     receiver: Receiver<PeerMessage>,
     net_writer: NetWriter<Output, OwnedWriteHalf>,
 }
 
 impl PeerProxy {
-    pub fn new(hub_t: HubTransmitter, receiver: Receiver<PeerMessage>, net_writer: NetWriter<Output, OwnedWriteHalf>) -> Self {
+    pub fn new(receiver: Receiver<PeerMessage>, net_writer: NetWriter<Output, OwnedWriteHalf>) -> Self {
         Self {
             receiver,
-            hub_tx: hub_t,
             net_writer,
         }
     }
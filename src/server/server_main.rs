@@ -1,88 +1,88 @@
-use flume::{unbounded};
-use futures::TryStreamExt;
-use log::*;
-use simple_logger::SimpleLogger;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::time::Duration;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
-use syncplay::server::*;
-use syncplay::server::actor_proto::{LogInAction, HubMessage};
-use syncplay::server::actor_proto::LogInAction::Connected;
-use syncplay::server::util::into_framed_split;
-use syncplay::server::net_proto::{Input, Output};
-use syncplay::server::net_proto::InputAction::Connect;
-use syncplay::server::peer::{PeerProxy, Peer};
-use syncplay::server::hub::Hub;
-use tokio::runtime::Handle;
 use anyhow::Context;
-use syncplay::ignore;
-
-const TIMEOUT: u64 = 20000;
+use log::*;
+use simple_logger::SimpleLogger;
+use structopt::StructOpt;
 
-#[tokio::main]
-async fn main() -> Res {
-    SimpleLogger::new().with_level(LevelFilter::Info).init().unwrap();
+use syncplay::server::{Res, Server, OK};
+use syncplay::server::config::ServerConfig;
 
-    let (hub_t, rx) = flume::unbounded();
-    let listener = TcpListener::bind("127.0.0.1:5135").await?;
-    // Spawn the hub
-    let handle = Handle::current();
-    std::thread::spawn(move || {
-        Hub::new(rx, handle).run();
-    });
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        info!("Connection accepted from : {}", addr);
-        let hub_t = hub_t.clone();
-        tokio::spawn(async move {
-            match handle_first_connection(socket, hub_t).await {
-                Ok(_) => ignore(),
-                Err(e) => error!("Error handling first connection: {}, from {}", e, addr)
-            }
-        });
-    }
+/// Server synchronizing the playback of its sessions.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "sync_daemon")]
+struct Opt {
+    /// TOML config file, the flags override its values
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Address to listen on, can be repeated
+    #[structopt(short, long)]
+    bind: Vec<SocketAddr>,
+    /// Milliseconds without input after which a peer is disconnected
+    #[structopt(long)]
+    peer_timeout: Option<u64>,
+    /// Milliseconds between two timestamps of a running session
+    #[structopt(long)]
+    session_tick: Option<u64>,
+    /// Milliseconds during which a disconnected peer can resume its connection
+    #[structopt(long)]
+    resume_grace: Option<u64>,
+    #[structopt(long)]
+    max_peers: Option<usize>,
+    #[structopt(long)]
+    max_sessions: Option<usize>,
+    #[structopt(long)]
+    max_participants: Option<usize>,
+    /// One of off, error, warn, info, debug, trace
+    #[structopt(short, long)]
+    log_level: Option<String>,
 }
 
-
-async fn handle_first_connection(stream: TcpStream, hub_t: HubTransmitter) -> Res<Peer> {
-    let (mut rs, ws) = into_framed_split::<Input, Output>(stream);
-
-    if rs.try_next().await? == Some(Input { from: None, action: Connect }) {
-        let (peer_t, peer_r) = unbounded();
-
-        let peer_id = PeerId::new_v4();
-        let peer = Peer {
-            id: peer_id,
-            pseudo: "".to_string(), // Not handled yet
-            proxy_tx: peer_t,
+impl Opt {
+    /// Config file, or the defaults, overridden by the flags.
+    fn into_config(self) -> Res<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
         };
+        if !self.bind.is_empty() {
+            config.bind = self.bind;
+        }
+        if let Some(peer_timeout) = self.peer_timeout {
+            config.peer_timeout_ms = peer_timeout;
+        }
+        if let Some(session_tick) = self.session_tick {
+            config.session_tick_ms = session_tick;
+        }
+        if let Some(resume_grace) = self.resume_grace {
+            config.resume_grace_ms = resume_grace;
+        }
+        if let Some(max_peers) = self.max_peers {
+            config.limits.max_peers = max_peers;
+        }
+        if let Some(max_sessions) = self.max_sessions {
+            config.limits.max_sessions = max_sessions;
+        }
+        if let Some(max_participants) = self.max_participants {
+            config.limits.max_participants = max_participants;
+        }
+        if let Some(level) = self.log_level {
+            config.log.level = level;
+        }
+        config.validate().context("Invalid configuration")?;
+        Ok(config)
+    }
+}
 
-        let peer_proxy_handle = {
-            let hub_t = hub_t.clone();
-            tokio::spawn(async move {
-                let error = PeerProxy::new(hub_t.clone(), peer_r, ws).run().await.unwrap_err();
-                info!("Peer: {} reader stopped, {}", peer_id, error);
-            })
-        };
+#[tokio::main]
+async fn main() -> Res {
+    let config = Opt::from_args().into_config()?;
+    SimpleLogger::new().with_level(config.log_level()?).init()?;
 
-        hub_t.send_async(HubMessage::LogInAction(Connected(peer.clone()))).await?;
-        info!("User logged, id created: {}", peer_id);
-        let mut event_loop = PeerEventReader {
-            net_reader: rs,
-            hub_tx: hub_t.clone(),
-        };
-        tokio::spawn(async move {
-            trace!("Starting user: {} event loop", peer_id);
-            {
-                let e = event_loop.run(Duration::from_millis(TIMEOUT), peer_id).await.unwrap_err();
-                info!("Connection to {} closed, error: {}", peer_id, e);
-            }
-            event_loop.hub_tx.send_async(HubMessage::LogInAction(LogInAction::Disconnect(peer_id))).await.context("Couldn't send to the hub").unwrap();
-            peer_proxy_handle.abort();
-        });
-        trace!("user : {}, event loop started", peer_id);
-        Ok(peer)
-    } else {
-        todo!("return error protocol")
-    }
-}
\ No newline at end of file
+    let server = Server::builder().config(config).start().await?;
+    tokio::signal::ctrl_c().await.context("Couldn't listen for Ctrl-C")?;
+    info!("Ctrl-C received");
+    server.shutdown().await?;
+    OK
+}
@@ -1,18 +1,22 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use flume::{Receiver, Sender, unbounded};
 use log::*;
-use tap::pipe::Pipe;
 use tokio::runtime::Handle;
 use tokio::select;
 use tokio::task::JoinHandle;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant, MissedTickBehavior};
 use uuid::Uuid;
+use anyhow::bail;
+use argon2::Argon2;
+use password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use password_hash::rand_core::OsRng;
 
 use crate::server::{PeerId, SessionId, Res, OK};
 use crate::server::actor_proto::SessionMessage;
-use crate::server::net_proto::{PlayerAction};
+use crate::server::net_proto::{ControlPolicy, PlayerAction};
 use crate::server::Output;
+use crate::server::util::now_micros;
 use crate::server::peer::Peer;
 use crate::server::session::State::Started;
 
@@ -23,64 +27,222 @@ pub enum SessionStatus {
     Interrupted,
 }
 
+/// Authoritative media position of a session.
+///
+/// The position is only stored at the last transition, the current one is computed from the
+/// elapsed time since `last_update` scaled by `rate` while the clock is running.
+#[derive(Clone, Copy, Debug)]
+pub struct PlaybackClock {
+    position: Duration,
+    rate: f64,
+    last_update: Instant,
+    running: bool,
+}
+
+impl Default for PlaybackClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlaybackClock {
+    pub fn new() -> Self {
+        PlaybackClock {
+            position: Duration::from_millis(0),
+            rate: 1.0,
+            last_update: Instant::now(),
+            running: false,
+        }
+    }
+
+    pub fn position(&self) -> Duration {
+        if self.running {
+            self.position + self.last_update.elapsed().mul_f64(self.rate)
+        } else {
+            self.position
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Fixes the current position, every modification of the clock must call it first.
+    fn update(&mut self) {
+        self.position = self.position();
+        self.last_update = Instant::now();
+    }
+
+    pub fn play(&mut self) {
+        self.update();
+        self.running = true;
+    }
+
+    pub fn pause(&mut self) {
+        self.update();
+        self.running = false;
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.update();
+        self.rate = rate;
+    }
+
+    pub fn set_position(&mut self, position: Duration) {
+        self.position = position;
+        self.last_update = Instant::now();
+    }
+}
+
 pub struct SessionProxy {
     participants: HashMap<PeerId, Peer>,
     receiver: Receiver<SessionMessage>,
     status: SessionStatus,
+    clock: PlaybackClock,
+    media: String,
 }
 
 impl SessionProxy {
-    pub fn run(&mut self, tick: Duration, id: SessionId) -> Res {
+    pub fn new(participants: HashMap<PeerId, Peer>, receiver: Receiver<SessionMessage>, media: String) -> Self {
+        SessionProxy {
+            participants,
+            receiver,
+            status: SessionStatus::Paused,
+            clock: PlaybackClock::new(),
+            media,
+        }
+    }
+
+    pub async fn run(&mut self, tick: Duration, id: SessionId) -> Res {
         info!("Session: {}, started", id);
+        // The participants present at the start load the media shared while the session waited
+        for peer in self.participants.values() {
+            self.catch_up(peer).await;
+        }
         let mut interval = tokio::time::interval(tick);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
         loop {
-            let message_fut = self.receiver.recv()?;
-            let message_to_send = match message_fut {
-                SessionMessage::Play => {
-
+            select! {
+                message = self.receiver.recv_async() => {
+                    self.handle(id, message?).await;
                 }
-                SessionMessage::Pause => {}
-                SessionMessage::Stop => {}
-            }
-            match self.status {
-                SessionStatus::Paused => message_fut.await?.pipe(|m| self.handle(id, m)),
-                SessionStatus::Playing => {
-                    select! {
-                        message = message_fut => {
-                            self.handle(id, message?);
-                        },
-                        _ = tick => {
-                            for (key, peer) in &self.participants {
-                                trace!("Send refresh tick to {}", key);
-                                peer.proxy_tx.send_async(Output::Timestamp(64)).await?;
-                            }
-                        }
-                    }
-                }
-                SessionStatus::Interrupted => {
-                    info!("Session {} interrupted", id);
-                    break Ok(());
+                _ = interval.tick() => {
+                    let position = self.clock.position();
+                    trace!("Session: {}, position {:?}", id, position);
+                    self.broadcast(Output::Timestamp {
+                        position: position.as_millis() as u64,
+                        server_time: now_micros(),
+                        playing: self.status == SessionStatus::Playing,
+                    }).await;
                 }
             }
+            if self.status == SessionStatus::Interrupted {
+                info!("Session {} interrupted", id);
+                break OK;
+            }
+        }
+    }
+
+    /// Sends the message to every participant, a participant that cannot be reached is only
+    /// logged, its disconnection is handled by the hub.
+    pub async fn broadcast(&self, message: Output) {
+        for (key, peer) in &self.participants {
+            if let Err(e) = peer.send_async(message.clone()).await {
+                warn!("Couldn't send {:?} to {}, error: {}", message, key, e);
+            }
         }
     }
 
-    pub fn handle(&mut self, id: SessionId, message: SessionMessage) {
-        info!("Session {}, transited to {:?}", id, message);
+    pub async fn handle(&mut self, id: SessionId, message: SessionMessage) {
         match message {
-            SessionMessage::Play => {
-                self.status = SessionStatus::Playing;
+            SessionMessage::Play(from) => {
+                self.transit(id, SessionStatus::Playing);
+                self.clock.play();
+                self.broadcast(Output::PlayerAction(from, PlayerAction::Play)).await;
+            }
+            SessionMessage::Pause(from) => {
+                self.transit(id, SessionStatus::Paused);
+                self.clock.pause();
+                self.broadcast(Output::PlayerAction(from, PlayerAction::Pause)).await;
             }
-            SessionMessage::Pause => {
-                self.status = SessionStatus::Paused;
+            SessionMessage::Stop(from) => {
+                self.transit(id, SessionStatus::Interrupted);
+                self.clock.pause();
+                self.broadcast(Output::PlayerAction(from, PlayerAction::Stop)).await;
             }
-            SessionMessage::Stop => {
-                self.status = SessionStatus::Interrupted;
+            SessionMessage::Seek(from, position) => {
+                info!("Session {}, seek to {:?}", id, position);
+                self.clock.set_position(position);
+                self.broadcast(Output::PlayerAction(from, PlayerAction::Seek(position.as_millis() as u64))).await;
             }
+            SessionMessage::Share(media) => {
+                info!("Session {}, media {} shared", id, media);
+                self.media = media;
+                self.transit(id, SessionStatus::Paused);
+                self.clock.pause();
+                self.clock.set_position(Duration::from_millis(0));
+                for peer in self.participants.values() {
+                    self.catch_up(peer).await;
+                }
+            }
+            SessionMessage::AddPeer(peer) => {
+                info!("Session {}, {} joined", id, peer.id);
+                self.catch_up(&peer).await;
+                self.participants.insert(peer.id, peer);
+            }
+            SessionMessage::RemovePeer(peer_id) => {
+                info!("Session {}, {} left", id, peer_id);
+                self.participants.remove(&peer_id);
+            }
+        }
+    }
+
+    fn transit(&mut self, id: SessionId, status: SessionStatus) {
+        info!("Session {}, transited to {:?}", id, status);
+        self.status = status;
+    }
+
+    /// Sends the running state of the session to a peer joining it.
+    async fn catch_up(&self, peer: &Peer) {
+        let catch_up = Output::CatchUp {
+            media: self.media.clone(),
+            position: self.clock.position().as_millis() as u64,
+            server_time: now_micros(),
+            playing: self.status == SessionStatus::Playing,
+        };
+        if let Err(e) = peer.send_async(catch_up).await {
+            warn!("Couldn't send the session state to {}, error: {}", peer.id, e);
         }
     }
 }
 
+/// Hashes the password with a random salt, an empty password makes the session public. It takes
+/// long by design, the hub runs it off its thread.
+pub fn hash_password(password: &str) -> Res<Option<String>> {
+    if password.is_empty() {
+        return Ok(None);
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("Couldn't hash the session password: {}", e))?;
+    Ok(Some(hash.to_string()))
+}
+
+/// Verifies the candidate against the hash, the comparison is done in constant time. Any password
+/// is accepted without hash.
+pub fn verify_password(hash: Option<&str>, candidate: &str) -> bool {
+    match hash {
+        None => true,
+        Some(hash) => PasswordHash::new(hash)
+            .map(|hash| Argon2::default().verify_password(candidate.as_bytes(), &hash).is_ok())
+            .unwrap_or(false),
+    }
+}
+
 #[derive(Debug)]
 enum State {
     Started(Sender<SessionMessage>, JoinHandle<()>, HashMap<PeerId, Peer>),
@@ -90,32 +252,46 @@ enum State {
 #[derive(Debug)]
 pub struct Session {
     id: SessionId,
-    password: String,
+    /// PHC string of the salted argon2 hash, none for a public session.
+    password: Option<String>,
     media: String,
     name: String,
     owner: PeerId,
+    policy: ControlPolicy,
+    controllers: HashSet<PeerId>,
     state: State,
 }
 
 impl Session {
-    pub fn new(owner: PeerId, name: String, mdp: String) -> Self {
-        let proxy = Session {
+    /// Creates a waiting session, the password is the hash returned by [`hash_password`].
+    pub fn new(owner: PeerId, name: String, password: Option<String>) -> Self {
+        Session {
             id: Uuid::new_v4(),
-            password: mdp.into(),
+            password,
             media: String::new(),
             name,
             owner,
+            policy: ControlPolicy::Everyone,
+            controllers: HashSet::new(),
             state: State::Waiting(HashMap::new()),
-        };
-        proxy
+        }
     }
 
     pub fn id(&self) -> SessionId {
         self.id
     }
 
-    pub fn password(&self) -> &str {
-        &self.password
+    pub fn is_public(&self) -> bool {
+        self.password.is_none()
+    }
+
+    /// Verifies the password against the stored hash, see [`verify_password`].
+    pub fn check_password(&self, candidate: &str) -> bool {
+        verify_password(self.password_hash(), candidate)
+    }
+
+    pub fn password_hash(&self) -> Option<&str> {
+        self.password.as_deref()
     }
 
     pub fn media(&self) -> &str {
@@ -128,75 +304,184 @@ impl Session {
 
     pub fn owner(&self) -> PeerId { self.owner }
 
+    pub fn policy(&self) -> ControlPolicy { self.policy }
+
+    pub fn can_control(&self, peer_id: PeerId) -> bool {
+        match self.policy {
+            ControlPolicy::Everyone => true,
+            ControlPolicy::Restricted => peer_id == self.owner || self.controllers.contains(&peer_id),
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: ControlPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn set_controller(&mut self, peer_id: PeerId, controller: bool) {
+        if controller {
+            self.controllers.insert(peer_id);
+        } else {
+            self.controllers.remove(&peer_id);
+        }
+    }
+
+    pub fn set_owner(&mut self, peer_id: PeerId) {
+        self.owner = peer_id;
+    }
+
+    /// Gives the ownership to a remaining participant, controllers first, when the owner is gone.
+    /// Returns the new owner.
+    pub fn elect_owner(&mut self) -> Option<PeerId> {
+        if self.contains_peer(self.owner) {
+            return None;
+        }
+        let new_owner = self.participants()
+            .map(|p| p.id)
+            .find(|id| self.controllers.contains(id))
+            .or_else(|| self.participants().map(|p| p.id).next())?;
+        self.controllers.remove(&new_owner);
+        self.owner = new_owner;
+        Some(new_owner)
+    }
+
+    pub fn permissions(&self) -> Output {
+        Output::Permissions {
+            owner: self.owner,
+            policy: self.policy,
+            controllers: self.controllers.iter().copied().collect(),
+        }
+    }
+
+    /// Replaces the media, a running session loads it in the players of its participants.
     pub fn set_media(&mut self, media: String) {
+        if let State::Started(ref sender, _, _) = self.state {
+            if let Err(e) = sender.send(SessionMessage::Share(media.clone())) {
+                warn!("Session {} is not running anymore: {}", self.id, e);
+            }
+        }
         self.media = media;
     }
 
-    pub fn set_password(&mut self, pwd: String) {
-        self.password = pwd;
+    pub fn set_password(&mut self, pwd: &str) -> Res {
+        self.password = hash_password(pwd)?;
+        OK
     }
 
+    /// Adds the peer, a running session sends it its current state.
     pub fn add_peer(&mut self, peer: Peer) {
         match self.state {
-            State::Started(_, _, _) => {
-                panic!("Invalid state");
+            State::Started(ref sender, _, ref mut participants) => {
+                participants.insert(peer.id, peer.clone());
+                if let Err(e) = sender.send(SessionMessage::AddPeer(peer)) {
+                    warn!("Session {} is not running anymore: {}", self.id, e);
+                }
             }
             State::Waiting(ref mut participants) => {
                 participants.insert(peer.id, peer);
             }
         }
     }
-    pub fn rm_peer(&mut self, peer_id: PeerId) -> Peer {
+
+    /// Replaces the copy of a participant, the running session doesn't need it as it only uses
+    /// the channel of the peer.
+    pub fn update_peer(&mut self, peer: Peer) {
+        match self.state {
+            State::Started(_, _, ref mut participants) | State::Waiting(ref mut participants) => {
+                if let Some(participant) = participants.get_mut(&peer.id) {
+                    *participant = peer;
+                }
+            }
+        }
+    }
+
+    /// Stops sending the session to a participant which lost its connection, it keeps its place.
+    pub fn detach_peer(&mut self, peer_id: PeerId) {
+        if let State::Started(ref sender, _, _) = self.state {
+            if let Err(e) = sender.send(SessionMessage::RemovePeer(peer_id)) {
+                warn!("Session {} is not running anymore: {}", self.id, e);
+            }
+        }
+    }
+
+    pub fn rm_peer(&mut self, peer_id: PeerId) -> Option<Peer> {
+        self.controllers.remove(&peer_id);
         match self.state {
-            State::Started(_, _, _) => panic!("Invalid state, no modifications"),
-            State::Waiting(ref mut participants) => participants.remove(&peer_id).unwrap()
+            State::Started(ref sender, _, ref mut participants) => {
+                let peer = participants.remove(&peer_id);
+                if let Err(e) = sender.send(SessionMessage::RemovePeer(peer_id)) {
+                    warn!("Session {} is not running anymore: {}", self.id, e);
+                }
+                peer
+            }
+            State::Waiting(ref mut participants) => participants.remove(&peer_id)
         }
     }
 
-    pub fn contains_peer(&self, _peer_id: PeerId) -> bool {
-        todo!("")
+    pub fn contains_peer(&self, peer_id: PeerId) -> bool {
+        self.participants_map().contains_key(&peer_id)
+    }
+
+    pub fn is_started(&self) -> bool {
+        matches!(self.state, Started(..))
+    }
+
+    /// Puts the session back to waiting when its running task ended, e.g. after a stop. Returns
+    /// true if it did.
+    pub fn reap(&mut self) -> bool {
+        match &mut self.state {
+            Started(sender, _, participants) if sender.is_disconnected() => {
+                info!("Session {} task ended, the session waits to be started again", self.id);
+                self.state = State::Waiting(std::mem::take(participants));
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn is_empty(&self) -> bool {
-        todo!("")
+        self.participants_map().is_empty()
     }
 
-    pub fn start(&mut self, refresh_tick: Duration, handle_runtime: &Handle) {
+    pub fn start(&mut self, refresh_tick: Duration, handle_runtime: &Handle) -> Res {
         self.state = match &self.state {
-            State::Started(_, _, _) => panic!("Invalid state, session already started"),
+            State::Started(_, _, _) => bail!("Session {} already started", self.id),
             State::Waiting(participants) => {
                 let (tx, rx) = unbounded();
                 let handle = {
                     let participants = participants.clone();
                     let id = self.id;
+                    let media = self.media.clone();
                     handle_runtime.spawn(async move {
-                        let mut session = SessionProxy {
-                            participants,
-                            receiver: rx,
-                            status: SessionStatus::Paused,
-                        };
-                        let e = session.run(refresh_tick, id).await.unwrap_err();
-                        warn!("Session {} encountered an error: {}", id, e);
+                        let mut session = SessionProxy::new(participants, rx, media);
+                        match session.run(refresh_tick, id).await {
+                            Ok(_) => info!("Session {} stopped", id),
+                            Err(e) => warn!("Session {} encountered an error: {}", id, e),
+                        }
                     })
                 };
                 Started(tx, handle, participants.clone())
             }
         };
+        OK
     }
 
-    pub fn handle_action(&mut self, action: PlayerAction) -> Res {
+    pub fn handle_action(&mut self, from: PeerId, action: PlayerAction) -> Res {
         if let Started(sender, _, _) = &mut self.state {
             match action {
                 PlayerAction::Play => {
-                    sender.send(SessionMessage::Play)?;
+                    sender.send(SessionMessage::Play(from))?;
                     OK
                 }
                 PlayerAction::Pause => {
-                    sender.send(SessionMessage::Pause)?;
+                    sender.send(SessionMessage::Pause(from))?;
                     OK
                 }
                 PlayerAction::Stop => {
-                    sender.send(SessionMessage::Stop)?;
+                    sender.send(SessionMessage::Stop(from))?;
+                    OK
+                }
+                PlayerAction::Seek(position) => {
+                    sender.send(SessionMessage::Seek(from, Duration::from_millis(position)))?;
                     OK
                 }
             }
@@ -205,7 +490,7 @@ impl Session {
         }
     }
 
-    pub fn participants(&self) -> impl Iterator<Item=&Peer> {
+    fn participants_map(&self) -> &HashMap<PeerId, Peer> {
         match &self.state {
             Started(_, _, participants) => {
                 participants
@@ -213,12 +498,87 @@ impl Session {
             State::Waiting(participants) => {
                 participants
             }
-        }.iter().map(|(_k, v)| v)
+        }
+    }
+
+    pub fn participants(&self) -> impl Iterator<Item=&Peer> {
+        self.participants_map().values()
     }
 
+    /// Stops the running session task, the session goes back to waiting with the same participants.
     pub fn stop(&mut self) {
-        todo!()
+        if let Started(_, handle, participants) = &mut self.state {
+            handle.abort();
+            info!("Session {} stopped", self.id);
+            self.state = State::Waiting(std::mem::take(participants));
+        }
     }
 }
 
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Started(_, handle, _) = &self.state {
+            handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Moves the clock in the past as if `elapsed` went by since its last transition.
+    fn elapse(clock: &mut PlaybackClock, elapsed: Duration) {
+        clock.last_update -= elapsed;
+    }
+
+    fn assert_near(position: Duration, expected_ms: u64) {
+        let ms = position.as_millis() as u64;
+        assert!((expected_ms..expected_ms + 50).contains(&ms), "{}ms instead of {}ms", ms, expected_ms);
+    }
+
+    #[test]
+    fn paused_clock_stays_still() {
+        let mut clock = PlaybackClock::new();
+        elapse(&mut clock, Duration::from_secs(2));
+        assert!(!clock.is_running());
+        assert_eq!(clock.position(), Duration::from_millis(0));
+    }
 
+    #[test]
+    fn running_clock_advances_at_its_rate() {
+        let mut clock = PlaybackClock::new();
+        clock.play();
+        elapse(&mut clock, Duration::from_secs(2));
+        assert_near(clock.position(), 2000);
+
+        clock.set_rate(1.5);
+        elapse(&mut clock, Duration::from_secs(2));
+        assert_near(clock.position(), 5000);
+        assert_eq!(clock.rate(), 1.5);
+    }
+
+    #[test]
+    fn pause_keeps_the_position_reached() {
+        let mut clock = PlaybackClock::new();
+        clock.play();
+        elapse(&mut clock, Duration::from_secs(3));
+        clock.pause();
+        let position = clock.position();
+        elapse(&mut clock, Duration::from_secs(3));
+        assert_eq!(clock.position(), position);
+        assert_near(position, 3000);
+    }
+
+    #[test]
+    fn set_position_restarts_from_it() {
+        let mut clock = PlaybackClock::new();
+        clock.play();
+        elapse(&mut clock, Duration::from_secs(3));
+        clock.set_position(Duration::from_secs(60));
+        assert!(clock.is_running());
+        assert_near(clock.position(), 60_000);
+        elapse(&mut clock, Duration::from_secs(1));
+        assert_near(clock.position(), 61_000);
+    }
+}
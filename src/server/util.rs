@@ -1,3 +1,8 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::{SinkExt, TryStreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use tap::pipe::Pipe;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
@@ -6,21 +11,53 @@ use tokio_serde::formats::SymmetricalBincode;
 use tokio_serde::SymmetricallyFramed;
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
+use crate::server::{Res, OK};
+
+/// Frames of a connection before they are typed, the handshake is read and written on them.
+pub type RawReader<Transport> = FramedRead<Transport, LengthDelimitedCodec>;
+pub type RawWriter<Transport> = FramedWrite<Transport, LengthDelimitedCodec>;
 pub type NetReader<Message, Transport> = SymmetricallyFramed<FramedRead<Transport, LengthDelimitedCodec>, Message, SymmetricalBincode<Message>>;
 pub type NetWriter<Message, Transport> = SymmetricallyFramed<FramedWrite<Transport, LengthDelimitedCodec>, Message, SymmetricalBincode<Message>>;
 
 pub fn net_reader<Message, T: AsyncRead>(read_stream: T) -> NetReader<Message, T> {
-    let length_delimited =
-        FramedRead::new(read_stream, LengthDelimitedCodec::new());
-    tokio_serde::SymmetricallyFramed::new(length_delimited, SymmetricalBincode::<Message>::default())
+    typed_reader(FramedRead::new(read_stream, LengthDelimitedCodec::new()))
 }
 
 pub fn net_writer<Message, T: AsyncWrite>(write_stream: T) -> NetWriter<Message, T> {
-    let length_delimited = FramedWrite::new(write_stream, LengthDelimitedCodec::new());
-    SymmetricallyFramed::new(length_delimited, SymmetricalBincode::<Message>::default())
+    typed_writer(FramedWrite::new(write_stream, LengthDelimitedCodec::new()))
 }
 
+/// Types the frames following the ones read raw, the frames already buffered are kept.
+pub fn typed_reader<Message, T: AsyncRead>(raw: RawReader<T>) -> NetReader<Message, T> {
+    SymmetricallyFramed::new(raw, SymmetricalBincode::<Message>::default())
+}
+
+pub fn typed_writer<Message, T: AsyncWrite>(raw: RawWriter<T>) -> NetWriter<Message, T> {
+    SymmetricallyFramed::new(raw, SymmetricalBincode::<Message>::default())
+}
 
 pub fn into_framed_split<ReaderMessage, WriteMessage>(a: TcpStream) -> (NetReader<ReaderMessage, OwnedReadHalf>, NetWriter<WriteMessage, OwnedWriteHalf>) {
     a.into_split().pipe(|(rs, ws)| (net_reader(rs), net_writer(ws)))
 }
+
+pub fn into_raw_split(a: TcpStream) -> (RawReader<OwnedReadHalf>, RawWriter<OwnedWriteHalf>) {
+    a.into_split().pipe(|(rs, ws)| (FramedRead::new(rs, LengthDelimitedCodec::new()), FramedWrite::new(ws, LengthDelimitedCodec::new())))
+}
+
+/// Reads a single message on the raw frames, none when the connection is closed.
+pub async fn read_frame<Message: DeserializeOwned + Unpin, T: AsyncRead + Unpin>(raw: &mut RawReader<T>) -> Res<Option<Message>> {
+    Ok(SymmetricallyFramed::new(raw, SymmetricalBincode::<Message>::default()).try_next().await?)
+}
+
+/// Writes a single message on the raw frames.
+pub async fn write_frame<Message: Serialize + Unpin, T: AsyncWrite + Unpin>(raw: &mut RawWriter<T>, message: Message) -> Res {
+    SymmetricallyFramed::new(raw, SymmetricalBincode::<Message>::default()).send(message).await?;
+    OK
+}
+
+/// Wall clock time in microseconds since the unix epoch, used to stamp clock synchronisation messages.
+pub fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
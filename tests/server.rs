@@ -0,0 +1,368 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use flume::{unbounded, Receiver, Sender};
+use futures::{SinkExt, TryStreamExt};
+use syncplay::client::{create_client, create_client_with, Client, ClientConfig};
+use syncplay::client::event::ClientEvent;
+use syncplay::client::player::{PlayerBackend, PlayerEvent, PlayerKind, PlayerState, SimulatedPlayer};
+use syncplay::server::{PeerId, Server};
+use syncplay::server::config::ServerConfig;
+use syncplay::server::net_proto::{Ack, ControlPolicy, HubAction, HubDelta, Hello, HelloReply, Input, InputAction, Output, OutputError, PlayerAction, Profile, Refusal, RequestId, Resume, ResumeToken, PROTOCOL_VERSION};
+use syncplay::server::util::{into_raw_split, read_frame, typed_reader, typed_writer, write_frame, NetReader, NetWriter};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::time::timeout;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Waits for the first event of the client matching `expected`, the others are skipped.
+async fn wait_for(client: &Client, expected: impl Fn(&ClientEvent) -> bool) -> ClientEvent {
+    let events = client.events();
+    timeout(WAIT, async {
+        loop {
+            let event = events.recv_async().await.expect("The client is stopped");
+            if expected(&event) {
+                break event;
+            }
+        }
+    }).await.expect("Event not received")
+}
+
+fn refusal(result: anyhow::Result<()>) -> Option<OutputError> {
+    result.err()?.downcast_ref::<OutputError>().copied()
+}
+
+/// Simulated player in which the test acts as the user.
+struct UserPlayer {
+    player: SimulatedPlayer,
+    user: Receiver<PlayerAction>,
+}
+
+impl PlayerBackend for UserPlayer {
+    fn load(&mut self, media: &str) -> anyhow::Result<()> { self.player.load(media) }
+    fn play(&mut self) -> anyhow::Result<()> { self.player.play() }
+    fn pause(&mut self) -> anyhow::Result<()> { self.player.pause() }
+    fn stop(&mut self) -> anyhow::Result<()> { self.player.stop() }
+    fn seek(&mut self, position: Duration) -> anyhow::Result<()> { self.player.seek(position) }
+    fn set_rate(&mut self, rate: f64) -> anyhow::Result<()> { self.player.set_rate(rate) }
+    fn position(&self) -> Duration { self.player.position() }
+    fn state(&self) -> PlayerState { self.player.state() }
+
+    fn poll(&mut self) -> Vec<PlayerEvent> {
+        let mut events = Vec::new();
+        for action in self.user.try_iter() {
+            match action {
+                PlayerAction::Play => self.player.play().unwrap(),
+                PlayerAction::Pause => self.player.pause().unwrap(),
+                PlayerAction::Stop => self.player.stop().unwrap(),
+                // Done by hand, the simulated player would report it as commanded
+                PlayerAction::Seek(position) => events.push(PlayerEvent::Seeked(Duration::from_millis(position))),
+            }
+        }
+        events.extend(self.player.poll());
+        events
+    }
+}
+
+/// Client whose player receives the actions of the user sent on the returned sender.
+async fn client_with_user(server: &Server) -> (Client, Sender<PlayerAction>) {
+    let (actions, user) = unbounded();
+    let player = PlayerKind::Custom(Arc::new(move || {
+        Ok(Box::new(UserPlayer { player: SimulatedPlayer::new(), user: user.clone() }) as Box<dyn PlayerBackend>)
+    }));
+    let client = create_client_with(server.local_addr(), ClientConfig { player, ..ClientConfig::default() }).await.unwrap();
+    (client, actions)
+}
+
+/// Connection speaking the protocol itself, to do what the `Client` never does.
+struct RawClient {
+    id: PeerId,
+    resume_token: ResumeToken,
+    resumed: bool,
+    reader: NetReader<Output, OwnedReadHalf>,
+    writer: NetWriter<Input, OwnedWriteHalf>,
+}
+
+impl RawClient {
+    async fn connect(server: &Server, resume: Option<Resume>) -> RawClient {
+        let stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (mut rs, mut ws) = into_raw_split(stream);
+        write_frame(&mut ws, PROTOCOL_VERSION).await.unwrap();
+        write_frame(&mut ws, Hello::new(Profile::default()).with_resume(resume)).await.unwrap();
+        let reply: HelloReply = read_frame(&mut rs).await.unwrap().unwrap();
+        assert_eq!(reply.refusal, None);
+        let mut reader = typed_reader(rs);
+        match timeout(WAIT, reader.try_next()).await.unwrap().unwrap() {
+            Some(Output::Connected { id, resume_token, resumed }) => RawClient { id, resume_token, resumed, reader, writer: typed_writer(ws) },
+            other => panic!("Expected the connection, received {:?}", other),
+        }
+    }
+
+    fn resume(&self) -> Resume {
+        Resume { peer: self.id, token: self.resume_token }
+    }
+
+    async fn send(&mut self, input: Input) {
+        self.writer.send(input).await.unwrap();
+    }
+
+    /// Next output, none when the server closed the connection.
+    async fn recv(&mut self) -> Option<Output> {
+        timeout(WAIT, self.reader.try_next()).await.expect("Output not received").ok().flatten()
+    }
+
+    /// Waits for the first output matching `expected`, the others are skipped.
+    async fn wait_for(&mut self, expected: impl Fn(&Output) -> bool) -> Output {
+        loop {
+            match self.recv().await {
+                Some(output) if expected(&output) => break output,
+                Some(_) => continue,
+                None => panic!("The server closed the connection"),
+            }
+        }
+    }
+
+    /// Sends the request and waits for its acknowledgement.
+    async fn request(&mut self, request: RequestId, action: InputAction) -> Ack {
+        self.send(Input::request(request, action)).await;
+        match self.wait_for(|o| matches!(o, Output::Ack(r, _) | Output::Refused(r, _) if *r == request)).await {
+            Output::Ack(_, ack) => ack,
+            refused => panic!("Request {} refused: {:?}", request, refused),
+        }
+    }
+
+    /// Waits for the server to close the connection.
+    async fn closed(&mut self) {
+        while self.recv().await.is_some() {}
+    }
+}
+
+#[tokio::test]
+async fn two_clients_play_a_session_together() {
+    let server = Server::builder().bind(([127, 0, 0, 1], 0)).start().await.unwrap();
+    let owner = create_client(server.local_addr()).await.unwrap();
+    let guest = create_client(server.local_addr()).await.unwrap();
+    wait_for(&owner, |e| matches!(e, ClientEvent::HubStateUpdated)).await;
+    wait_for(&guest, |e| matches!(e, ClientEvent::HubStateUpdated)).await;
+
+    let session = owner.create_session("movie night".to_string(), "secret".to_string()).await.unwrap();
+    assert_eq!(refusal(guest.join_session(session, "wrong".to_string()).await), Some(OutputError::PasswordDoesntMatch));
+    guest.join_session(session, "secret".to_string()).await.unwrap();
+    wait_for(&guest, |e| matches!(e, ClientEvent::SessionJoined(id) if *id == session)).await;
+
+    owner.start_session().await.unwrap();
+    assert_eq!(refusal(owner.start_session().await), Some(OutputError::InvalidRequest));
+
+    owner.play().await.unwrap();
+    for client in [&owner, &guest] {
+        wait_for(client, |e| matches!(e, ClientEvent::Playback(_, PlayerAction::Play))).await;
+    }
+
+    timeout(WAIT, server.shutdown()).await
+        .expect("The server didn't stop")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn resumed_connection_closes_the_previous_one() {
+    let server = Server::builder().bind(([127, 0, 0, 1], 0)).start().await.unwrap();
+    let mut previous = RawClient::connect(&server, None).await;
+    let mut resumed = RawClient::connect(&server, Some(previous.resume())).await;
+    assert!(resumed.resumed);
+    assert_eq!(resumed.id, previous.id);
+
+    previous.closed().await;
+    resumed.send(Input::request(1, InputAction::HubAction(HubAction::Resync))).await;
+    resumed.wait_for(|o| matches!(o, Output::Ack(1, _))).await;
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn media_shared_before_the_start_is_loaded_by_the_participants() {
+    let server = Server::builder().bind(([127, 0, 0, 1], 0)).start().await.unwrap();
+    let mut owner = RawClient::connect(&server, None).await;
+    let mut guest = RawClient::connect(&server, None).await;
+
+    owner.send(Input::request(1, InputAction::HubAction(HubAction::CreateSession("movie night".to_string(), String::new())))).await;
+    let session = match owner.wait_for(|o| matches!(o, Output::Ack(1, _))).await {
+        Output::Ack(_, Ack::SessionCreated(session)) => session,
+        other => panic!("Session not created: {:?}", other),
+    };
+    guest.send(Input::request(1, InputAction::HubAction(HubAction::Join(session, String::new())))).await;
+    guest.wait_for(|o| matches!(o, Output::Ack(1, Ack::Done))).await;
+    owner.send(Input::request(2, InputAction::HubAction(HubAction::Share("movie.mkv".to_string())))).await;
+    owner.wait_for(|o| matches!(o, Output::Ack(2, Ack::Done))).await;
+
+    owner.send(Input::request(3, InputAction::HubAction(HubAction::SessionStart))).await;
+    for client in [&mut owner, &mut guest] {
+        match client.wait_for(|o| matches!(o, Output::CatchUp { .. })).await {
+            Output::CatchUp { media, position, playing, .. } => {
+                assert_eq!(media, "movie.mkv");
+                assert_eq!(position, 0);
+                assert!(!playing);
+            }
+            _ => unreachable!(),
+        }
+    }
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn participants_see_the_playback_of_each_other() {
+    let server = Server::builder().bind(([127, 0, 0, 1], 0)).start().await.unwrap();
+    let owner = create_client(server.local_addr()).await.unwrap();
+    let guest = create_client(server.local_addr()).await.unwrap();
+    wait_for(&owner, |e| matches!(e, ClientEvent::HubStateUpdated)).await;
+    let owner_id = owner.hub_state().unwrap().me.id;
+
+    let session = owner.create_session("movie night".to_string(), String::new()).await.unwrap();
+    guest.join_session(session, String::new()).await.unwrap();
+    owner.start_session().await.unwrap();
+
+    timeout(WAIT, async {
+        while !guest.peer_sync().contains_key(&owner_id) {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }).await.expect("No sync report received");
+    assert!(!guest.peer_sync()[&owner_id].playing);
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn closed_and_dropped_clients_leave_the_server() {
+    let config = ServerConfig { resume_grace_ms: 100, ..ServerConfig::default() };
+    let server = Server::builder().config(config).bind(([127, 0, 0, 1], 0)).start().await.unwrap();
+    let observer = create_client(server.local_addr()).await.unwrap();
+    let closed = create_client(server.local_addr()).await.unwrap();
+    let dropped = create_client(server.local_addr()).await.unwrap();
+    timeout(WAIT, async {
+        while observer.connected_peers().len() < 3 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }).await.expect("The clients didn't connect");
+
+    timeout(WAIT, closed.close()).await.expect("The client didn't close").unwrap();
+    drop(dropped);
+    timeout(WAIT, async {
+        while observer.connected_peers().len() > 1 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }).await.expect("The clients are still connected");
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn detached_peer_resumes_its_place_in_the_session() {
+    let server = Server::builder().bind(([127, 0, 0, 1], 0)).start().await.unwrap();
+    let mut owner = RawClient::connect(&server, None).await;
+    let mut guest = RawClient::connect(&server, None).await;
+    let session = match owner.request(1, InputAction::HubAction(HubAction::CreateSession("movie night".to_string(), String::new()))).await {
+        Ack::SessionCreated(session) => session,
+        other => panic!("Session not created: {:?}", other),
+    };
+    guest.request(1, InputAction::HubAction(HubAction::Join(session, String::new()))).await;
+    owner.request(2, InputAction::HubAction(HubAction::Share("movie.mkv".to_string()))).await;
+    owner.request(3, InputAction::HubAction(HubAction::SessionStart)).await;
+    guest.wait_for(|o| matches!(o, Output::CatchUp { .. })).await;
+
+    let resume = guest.resume();
+    drop(guest);
+    // The session goes on while the guest is away
+    owner.request(4, InputAction::SessionAction(PlayerAction::Play)).await;
+    let mut guest = RawClient::connect(&server, Some(resume)).await;
+    assert!(guest.resumed);
+    assert_eq!(guest.id, resume.peer);
+    match guest.wait_for(|o| matches!(o, Output::World(_))).await {
+        Output::World(state) => {
+            let participants = state.my_session.and_then(|s| s.participants).expect("The guest lost its session");
+            assert!(participants.iter().any(|p| p.id == resume.peer));
+        }
+        _ => unreachable!(),
+    }
+    match guest.wait_for(|o| matches!(o, Output::CatchUp { .. })).await {
+        Output::CatchUp { media, playing, .. } => {
+            assert_eq!(media, "movie.mkv");
+            assert!(playing);
+        }
+        _ => unreachable!(),
+    }
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn resume_token_expires_after_the_grace_period() {
+    let config = ServerConfig { resume_grace_ms: 100, ..ServerConfig::default() };
+    let server = Server::builder().config(config).bind(([127, 0, 0, 1], 0)).start().await.unwrap();
+    let mut observer = RawClient::connect(&server, None).await;
+    let previous = RawClient::connect(&server, None).await;
+    let resume = previous.resume();
+
+    drop(previous);
+    observer.wait_for(|o| matches!(o, Output::HubDelta { delta: HubDelta::PeerDisconnected(id), .. } if *id == resume.peer)).await;
+    let next = RawClient::connect(&server, Some(resume)).await;
+    assert!(!next.resumed);
+    assert_ne!(next.id, resume.peer);
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn other_protocol_versions_are_refused() {
+    let server = Server::builder().bind(([127, 0, 0, 1], 0)).start().await.unwrap();
+    let stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    let (mut rs, mut ws) = into_raw_split(stream);
+    write_frame(&mut ws, PROTOCOL_VERSION + 1).await.unwrap();
+
+    let reply: HelloReply = timeout(WAIT, read_frame(&mut rs)).await.unwrap().unwrap().unwrap();
+    assert_eq!(reply.version, PROTOCOL_VERSION);
+    assert_eq!(reply.refusal, Some(Refusal::InvalidProtocol));
+    let next: Option<HelloReply> = timeout(WAIT, read_frame(&mut rs)).await.unwrap().unwrap_or_default();
+    assert_eq!(next, None, "The connection stayed open");
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn inputs_sent_as_another_peer_are_refused() {
+    let server = Server::builder().bind(([127, 0, 0, 1], 0)).start().await.unwrap();
+    let mut forger = RawClient::connect(&server, None).await;
+    let victim = RawClient::connect(&server, None).await;
+
+    let resync = InputAction::HubAction(HubAction::Resync);
+    forger.send(Input { from: Some(victim.id), request: Some(1), action: resync.clone() }).await;
+    assert!(matches!(forger.wait_for(|o| matches!(o, Output::Refused(1, _))).await, Output::Refused(1, OutputError::ForgedIdentity)));
+    // The connection stays open, the inputs sent as itself are applied
+    forger.send(Input { from: Some(forger.id), request: Some(2), action: resync }).await;
+    forger.wait_for(|o| matches!(o, Output::Ack(2, Ack::Done))).await;
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn actions_done_in_the_player_are_sent_to_the_session() {
+    let server = Server::builder().bind(([127, 0, 0, 1], 0)).start().await.unwrap();
+    let (owner, owner_actions) = client_with_user(&server).await;
+    let (guest, guest_actions) = client_with_user(&server).await;
+    wait_for(&owner, |e| matches!(e, ClientEvent::HubStateUpdated)).await;
+    let owner_id = owner.hub_state().unwrap().me.id;
+
+    let session = owner.create_session("movie night".to_string(), String::new()).await.unwrap();
+    guest.join_session(session, String::new()).await.unwrap();
+    owner.set_control_policy(ControlPolicy::Restricted).await.unwrap();
+    owner.start_session().await.unwrap();
+    // Lets the participants catch up the session
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    owner_actions.send(PlayerAction::Play).unwrap();
+    wait_for(&guest, |e| matches!(e, ClientEvent::Playback(from, PlayerAction::Play) if *from == owner_id)).await;
+    owner_actions.send(PlayerAction::Seek(5000)).unwrap();
+    wait_for(&guest, |e| matches!(e, ClientEvent::Playback(from, PlayerAction::Seek(5000)) if *from == owner_id)).await;
+
+    // The guest can't control the session, its player goes back to the session playback
+    guest_actions.send(PlayerAction::Pause).unwrap();
+    wait_for(&guest, |e| matches!(e, ClientEvent::ActionRefused(PlayerAction::Pause, OutputError::Unauthorized))).await;
+    timeout(WAIT, async {
+        while guest.player().state != PlayerState::Playing {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }).await.expect("The player stayed paused");
+    server.shutdown().await.unwrap();
+}